@@ -0,0 +1,33 @@
+-- Follows MIDI clock from a DAW. Pad 1 taps A in time with the music: held
+-- down, it presses A on every eighth note until it's let go. The downbeat of
+-- every bar flashes B.
+--
+-- The DAW needs to send MIDI clock to the port, and the transport has to be
+-- running for beats and timers to fire.
+
+local pad = nil
+local tapping = nil
+
+function on_script_init()
+    midi.open(1)
+    pad = gamepad.create()
+
+    map.layer(map.BASE).bind(map.note(36), map.macro(function(_, pressed)
+        if pressed and not tapping then
+            tapping = timer.every_beats(0.5, function()
+                pad.button(gamepad.BTN_A, true)
+                -- Let go a sixteenth later, so the game sees separate presses
+                timer.after_beats(0.25, function()
+                    pad.button(gamepad.BTN_A, false)
+                end)
+            end)
+        elseif not pressed and tapping then
+            tapping.cancel()
+            tapping = nil
+        end
+    end))
+end
+
+function on_beat(beat)
+    pad.button(gamepad.BTN_B, beat == 1)
+end
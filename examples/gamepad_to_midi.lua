@@ -0,0 +1,23 @@
+-- Turns a gamepad into a MIDI controller: face buttons play notes, the left
+-- stick bends pitch and the triggers send mod wheel and expression.
+-- Pick "handcake gamepad" as a MIDI input in your DAW.
+
+local ABS_Z = 0x02
+local ABS_RZ = 0x05
+
+function on_script_init()
+    local dev = evdev.open("Controller", {grab = true})
+    local out = midi.create_virtual_output("handcake gamepad")
+
+    local base = map.layer(map.BASE)
+    base.bind(map.evdev_key(gamepad.BTN_A, dev), map.midi_note(out, 1, 60))
+    base.bind(map.evdev_key(gamepad.BTN_B, dev), map.midi_note(out, 1, 62))
+    base.bind(map.evdev_key(gamepad.BTN_X, dev), map.midi_note(out, 1, 64))
+    base.bind(map.evdev_key(gamepad.BTN_Y, dev), map.midi_note(out, 1, 67, {velocity = 100}))
+
+    base.bind(map.evdev_axis(gamepad.AXIS_LSTICK_Y, dev), map.midi_pitch_bend(out, 1, {invert = true}))
+    -- Real pads report their triggers on ABS_Z and ABS_RZ, unlike handcake's
+    -- own virtual one
+    base.bind(map.evdev_axis(ABS_Z, dev), map.midi_cc(out, 1, 1))
+    base.bind(map.evdev_axis(ABS_RZ, dev), map.midi_cc(out, 1, 11))
+end
@@ -0,0 +1,23 @@
+-- Keeps going when a controller is unplugged. MIDI ports and input devices
+-- opened with midi.open, midi.open_output and evdev.open are reconnected by
+-- themselves when they come back; this just lets you know.
+
+local pad = nil
+
+function on_script_init()
+    midi.open(1)
+    pad = gamepad.create()
+    map.layer(map.BASE).bind(map.note(36), map.button(pad, gamepad.BTN_A))
+end
+
+-- device is {kind = "midi" or "evdev", name = ..., path = ..., id = ...}.
+-- id is the handle evdev.open gave back, if the script had it open.
+function on_device_added(device)
+    print(("%s device plugged in: %s"):format(device.kind, device.name))
+end
+
+function on_device_removed(device)
+    print(("%s device unplugged: %s"):format(device.kind, device.name))
+    -- Let go of anything held down when the controller went away
+    pad.button(gamepad.BTN_A, false)
+end
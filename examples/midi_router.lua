@@ -0,0 +1,20 @@
+-- Exposes a pair of virtual MIDI ports: anything sent to "handcake in" comes
+-- back out of "handcake out" an octave higher. Connect them up with aconnect
+-- or your DAW's MIDI settings.
+
+local out = nil
+
+function on_script_init()
+    midi.create_virtual_input("handcake in")
+    out = midi.create_virtual_output("handcake out")
+end
+
+function on_midi_recv(ev)
+    if ev.event == "note_on" then
+        out.note_on(ev.channel, math.min(ev.key + 12, 127), ev.vel)
+    elseif ev.event == "note_off" then
+        out.note_off(ev.channel, math.min(ev.key + 12, 127), ev.vel)
+    elseif ev.event == "control_change" then
+        out.cc(ev.channel, ev.control, ev.value)
+    end
+end
@@ -9,6 +9,8 @@ local pad = nil
 function on_script_init()
     midi.open(1)
     pad = gamepad.create()
+    -- the MPK's stick is a bit twitchy around the centre
+    pad.configure_stick(gamepad.STICK_LEFT, {deadzone = 0.05, square = true})
 end
 
 local js = {x=0, y=0}
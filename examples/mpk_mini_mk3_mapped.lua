@@ -0,0 +1,33 @@
+-- The same mapping as mpk_mini_mk3.lua, but using the mapping engine
+-- Holding pad 8 (note 51) switches the face buttons over to the keyboard
+
+local pad = nil
+local kbd = nil
+
+function on_script_init()
+    midi.open(1)
+    pad = gamepad.create()
+    kbd = keyboard.create()
+
+    local base = map.layer(map.BASE)
+    base.bind(map.note(37), map.button(pad, gamepad.BTN_A))
+    base.bind(map.note(36), map.button(pad, gamepad.BTN_B))
+    base.bind(map.note(40), map.button(pad, gamepad.BTN_Y))
+    base.bind(map.note(38), map.button(pad, gamepad.BTN_X))
+    base.bind(map.note(46), map.button(pad, gamepad.BTN_RB))
+    base.bind(map.note(44), map.button(pad, gamepad.BTN_LB))
+    base.bind(map.note(42), map.axis(pad, gamepad.AXIS_RTRIGGER))
+    base.bind(map.note(54), map.axis(pad, gamepad.AXIS_LTRIGGER))
+
+    base.bind(map.cc(1), map.axis(pad, gamepad.AXIS_LSTICK_X, {min = 0, max = -1}))
+    base.bind(map.cc(2), map.axis(pad, gamepad.AXIS_LSTICK_X, {min = 0, max = 1}))
+    base.bind(map.pitch_bend(), map.axis(pad, gamepad.AXIS_LSTICK_Y, {invert = true}))
+
+    base.bind(map.note(51), map.momentary("keys"))
+
+    local keys = map.layer("keys")
+    keys.bind(map.note(37), map.key(kbd, keyboard.KEY_ENTER))
+    keys.bind(map.note(36), map.key(kbd, keyboard.KEY_ESC))
+    keys.bind(map.note(40), map.key(kbd, keyboard.KEY_UP))
+    keys.bind(map.note(38), map.key(kbd, keyboard.KEY_DOWN))
+end
@@ -0,0 +1,29 @@
+-- Tests for mpk_mini_mk3_mapped.lua, run with:
+-- handcake test examples/mpk_mini_mk3_test.lua -s examples/mpk_mini_mk3_mapped.lua
+--
+-- Devices are numbered in the order the script creates them: 1 is the
+-- gamepad, 2 is the keyboard
+
+test("pad 1 presses A", function()
+    sim.midi.note_on(1, 37, 100)
+    assert_eq(sim.button(gamepad.BTN_A), true)
+    sim.midi.note_off(1, 37)
+    assert_eq(sim.button(gamepad.BTN_A), false)
+end)
+
+test("knobs move the left stick", function()
+    sim.midi.cc(1, 2, 127)
+    assert_near(sim.axis(gamepad.AXIS_LSTICK_X), 1.0, 0.01)
+    sim.midi.cc(1, 2, 0)
+    assert_near(sim.axis(gamepad.AXIS_LSTICK_X), 0.0, 0.01)
+end)
+
+test("holding pad 8 switches to the keyboard", function()
+    sim.midi.note_on(1, 51, 100)
+    sim.midi.note_on(1, 37, 100)
+    assert_eq(sim.key(keyboard.KEY_ENTER, 2), true)
+    assert_eq(sim.button(gamepad.BTN_A), false)
+    sim.midi.note_off(1, 37)
+    sim.midi.note_off(1, 51)
+    assert_eq(sim.key(keyboard.KEY_ENTER, 2), false)
+end)
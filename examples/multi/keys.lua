@@ -0,0 +1,28 @@
+-- The keyboard half of pads.lua: notes on channel 1 type letters, from a
+-- different row depending on the bank pads.lua last announced.
+local ROWS = {
+    [0] = {"A", "S", "D", "F", "G", "H", "J", "K"},
+    [1] = {"Q", "W", "E", "R", "T", "Y", "U", "I"},
+}
+local bank = 0
+
+function on_script_init()
+    midi.open(1)
+    kbd = keyboard.create()
+end
+
+function on_bus_recv(topic, value, from)
+    if topic == "bank" and ROWS[value.number] then
+        print(("%s switched to bank %d"):format(from, value.number))
+        bank = value.number
+    end
+end
+
+function on_midi_recv(ev)
+    if ev.is_note and ev.channel == 1 then
+        local key = ROWS[bank][ev.key - 59]
+        if key then
+            kbd.key(keyboard["KEY_" .. key], ev.event == "note_on" and ev.vel > 0)
+        end
+    end
+end
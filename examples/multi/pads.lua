@@ -0,0 +1,25 @@
+-- Run the whole directory with `handcake -s examples/multi --socket`. Each
+-- script has its own Lua state, so either can be turned off on its own with
+-- {"cmd": "disable", "script": "pads"} and back on with "enable".
+
+-- Drum pads on channel 10 press gamepad buttons, and the knobs tell the
+-- other scripts which bank they're on.
+local PADS = {
+    [36] = gamepad.BTN_A,
+    [37] = gamepad.BTN_B,
+    [38] = gamepad.BTN_X,
+    [39] = gamepad.BTN_Y,
+}
+
+function on_script_init()
+    midi.open(1)
+    pad = gamepad.create()
+end
+
+function on_midi_recv(ev)
+    if ev.is_note and ev.channel == 10 and PADS[ev.key] then
+        pad.button(PADS[ev.key], ev.event == "note_on" and ev.vel > 0)
+    elseif ev.event == "program_change" then
+        bus.publish("bank", {number = ev.program})
+    end
+end
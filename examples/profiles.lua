@@ -0,0 +1,28 @@
+-- GlovePIE-style per-game mappings: the pads drive a gamepad, but while a
+-- Steam game (or anything with "Dolphin" in the title) has focus they're
+-- remapped. Works on X11, sway and Hyprland.
+
+local pad = nil
+
+function on_script_init()
+    midi.open(1)
+    pad = gamepad.create()
+
+    local base = map.layer(map.BASE)
+    base.bind(map.note(36), map.button(pad, gamepad.BTN_A))
+    base.bind(map.note(37), map.button(pad, gamepad.BTN_B))
+
+    -- Portal 2 wants jump on the left pad
+    local portal = map.layer("portal")
+    portal.bind(map.note(36), map.button(pad, gamepad.BTN_B))
+    portal.bind(map.note(37), map.button(pad, gamepad.BTN_A))
+    focus.profile("steam_app_620", "portal")
+
+    local dolphin = map.layer("dolphin")
+    dolphin.bind(map.note(36), map.button(pad, gamepad.BTN_X))
+    focus.profile({title = "Dolphin"}, "dolphin")
+end
+
+function on_focus_change(app_id, title)
+    print(("now in %s (%s)"):format(app_id, title))
+end
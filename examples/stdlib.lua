@@ -0,0 +1,51 @@
+-- Shows off handcake.util: pads bound by note name, a knob with a deadzone
+-- and a gentler curve, and a state machine for a "shift" mode toggled by
+-- double-tapping pad 8. Modules next to this script can be required too.
+
+local util = require("handcake.util")
+
+local pad = nil
+local shift = nil
+
+function on_script_init()
+    midi.open(1)
+    pad = gamepad.create()
+
+    local base = map.layer(map.BASE)
+    util.bind_notes(base, {
+        ["C#2"] = map.button(pad, gamepad.BTN_A),
+        C2 = map.button(pad, gamepad.BTN_B),
+        E2 = map.button(pad, gamepad.BTN_Y),
+        D2 = map.button(pad, gamepad.BTN_X),
+    })
+
+    local stick = util.chain(util.curves.deadzone(0.1), util.curves.power(1.5))
+    util.bind_ccs(base, { [1] = util.curved_axis(pad, gamepad.AXIS_LSTICK_X, stick) })
+
+    shift = util.state_machine {
+        initial = "off",
+        states = {
+            off = { on = { tap = "once" } },
+            once = {
+                enter = function(sm, t) sm.tapped = t end,
+                on = {
+                    tap = function(sm, t)
+                        if t - sm.tapped < 0.3 then return "on" end
+                        sm.tapped = t
+                    end,
+                },
+            },
+            on = {
+                enter = function() print("shift on") end,
+                exit = function() print("shift off") end,
+                on = { tap = "off" },
+            },
+        },
+    }
+end
+
+function on_midi_recv(ev)
+    if ev.event == "note_on" and ev.key == util.note("D#3") then
+        shift:send("tap", misc.time())
+    end
+end
@@ -0,0 +1,38 @@
+-- Uses when notes arrive: a quick double tap on pad 1 presses B instead of
+-- A, and the tempo of taps on pad 2 is printed.
+--
+-- Every MIDI and input event has `received_ns`, when handcake got it on the
+-- same clock as misc.now_ns(), and MIDI events have `timestamp_us` from the
+-- device driver, which is more precise but only comparable between events
+-- from the same port. OSC gets it as the third argument to on_osc_recv.
+
+local DOUBLE_TAP_NS = 250 * 1000 * 1000
+
+local pad = nil
+local last_tap = nil
+local taps = {}
+
+function on_script_init()
+    midi.open(1)
+    pad = gamepad.create()
+end
+
+function on_midi_recv(ev)
+    if ev.event == "note_on" and ev.key == 36 then
+        local double = last_tap and ev.received_ns - last_tap < DOUBLE_TAP_NS
+        last_tap = ev.received_ns
+        pad.button(double and gamepad.BTN_B or gamepad.BTN_A, true)
+    elseif ev.event == "note_off" and ev.key == 36 then
+        pad.button(gamepad.BTN_A, false)
+        pad.button(gamepad.BTN_B, false)
+    elseif ev.event == "note_on" and ev.key == 37 then
+        table.insert(taps, ev.timestamp_us)
+        if #taps > 4 then
+            table.remove(taps, 1)
+        end
+        if #taps == 4 then
+            local beat_us = (taps[4] - taps[1]) / 3
+            print(("%.1f BPM"):format(60 * 1000 * 1000 / beat_us))
+        end
+    end
+end
@@ -0,0 +1,34 @@
+-- Drives a virtual gamepad from a TouchOSC layout. Point TouchOSC at this
+-- machine on port 8000; button and fader states are echoed back on 9000 so
+-- the layout stays in sync.
+
+local pad = nil
+local phone = nil
+
+local buttons = {
+    ["/1/a"] = gamepad.BTN_A,
+    ["/1/b"] = gamepad.BTN_B,
+    ["/1/x"] = gamepad.BTN_X,
+    ["/1/y"] = gamepad.BTN_Y,
+}
+
+function on_script_init()
+    pad = gamepad.create()
+    osc.listen(8000)
+end
+
+function on_osc_recv(addr, args)
+    local btn = buttons[addr]
+    if btn ~= nil then
+        pad.button(btn, args[1] > 0.5)
+    elseif addr == "/1/xy" then
+        -- XY pads send two floats in 0..1
+        pad.axis(gamepad.AXIS_LSTICK_X, args[1] * 2 - 1)
+        pad.axis(gamepad.AXIS_LSTICK_Y, args[2] * 2 - 1)
+    elseif addr == "/ping" and phone ~= nil then
+        osc.send(phone, 9000, "/pong")
+    elseif addr == "/hello" then
+        -- Lets the phone tell us where it is
+        phone = args[1]
+    end
+end
@@ -0,0 +1,28 @@
+use mlua::LuaSerdeExt;
+use serde_json::Value;
+
+use crate::{Message, script};
+use super::ApiProvider;
+
+/// Lets scripts talk to each other. Anything published goes to
+/// `on_bus_recv(topic, value, from)` in every other running script, where
+/// `from` is the sending script's name.
+pub struct Bus;
+impl ApiProvider for Bus {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        let tab = l.create_table()?;
+
+        // Values go through JSON, so tables of functions or userdata can't be sent
+        tab.set("publish", l.create_function(|l, (topic, value): (String, mlua::Value)| {
+            let data = l.from_value::<Value>(value)?;
+            let _ = script::sender(l).send(Message::Bus { from: script::id(l), topic, data });
+            Ok(())
+        })?)?;
+
+        l.globals().set("bus", tab)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+use std::{collections::VecDeque, sync::Arc, time::{Duration, Instant}};
+use mlua::RegistryKey;
+
+use super::{ApiProvider, midi::MidiTime};
+
+// Follows MIDI clock from a DAW or drum machine: 24 ticks to the beat, with
+// start, stop, continue and song position messages moving the transport.
+// Beats and timers only run while the transport does, but the tempo is
+// tracked from whatever clock comes in.
+
+/// MIDI clock sends this many ticks per quarter note.
+pub const TICKS_PER_BEAT: u64 = 24;
+
+/// Song position pointers count in sixteenth notes.
+const TICKS_PER_SIXTEENTH: u64 = 6;
+
+/// Tempo is averaged over this many tick intervals, one beat's worth.
+const TEMPO_WINDOW: usize = TICKS_PER_BEAT as usize;
+
+/// Longer than this without a tick and the clock is taken to have gone away.
+const CLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// MIDI system real-time and song position messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockEvent {
+    Tick,
+    Start,
+    Continue,
+    Stop,
+    /// In sixteenth notes from the start of the song.
+    Position(u16),
+}
+
+impl ClockEvent {
+    /// Picks clock messages out of raw MIDI, which midi_control doesn't know about.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match data {
+            [0xf8] => Some(ClockEvent::Tick),
+            [0xfa] => Some(ClockEvent::Start),
+            [0xfb] => Some(ClockEvent::Continue),
+            [0xfc] => Some(ClockEvent::Stop),
+            [0xf2, lsb, msb] => Some(ClockEvent::Position((*msb as u16) << 7 | (*lsb & 0x7f) as u16)),
+            _ => None,
+        }
+    }
+}
+
+struct Timer {
+    id: u64,
+    f: Arc<RegistryKey>,
+    /// In ticks, for timers that repeat.
+    every: Option<f64>,
+    /// Position in ticks it's next due at.
+    due: f64,
+}
+
+struct Clock {
+    running: bool,
+    /// Where the song is, in ticks from the start: the last tick, or where
+    /// the transport was moved to.
+    now: u64,
+    /// Position of the next tick.
+    ticks: u64,
+    beats_per_bar: u64,
+    /// Times of recent ticks in microseconds, for working out the tempo.
+    recent: VecDeque<f64>,
+    last_tick: Option<Instant>,
+    timers: Vec<Timer>,
+    next_timer: u64,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self {
+            running: false,
+            now: 0,
+            ticks: 0,
+            beats_per_bar: 4,
+            recent: VecDeque::new(),
+            last_tick: None,
+            timers: vec![],
+            next_timer: 1,
+        }
+    }
+}
+
+impl Clock {
+    fn bpm(&self) -> Option<f64> {
+        if self.last_tick.map_or(true, |x| super::misc::now().saturating_duration_since(x) > CLOCK_TIMEOUT) || self.recent.len() < 2 {
+            return None;
+        }
+        let span = self.recent.back()? - self.recent.front()?;
+        let per_tick = span / (self.recent.len() - 1) as f64;
+        (per_tick > 0.0).then(|| 60_000_000.0 / (per_tick * TICKS_PER_BEAT as f64))
+    }
+
+    /// The first tick at or after the current position that's a multiple of `every`.
+    fn align(&self, every: f64) -> f64 {
+        (self.ticks as f64 / every).ceil() * every
+    }
+
+    /// Moves the transport, keeping one-shot timers the same distance away
+    /// and lining repeating ones up with the new position.
+    fn jump(&mut self, to: u64) {
+        let delta = to as f64 - self.now as f64;
+        self.now = to;
+        self.ticks = to;
+        for i in 0..self.timers.len() {
+            self.timers[i].due = match self.timers[i].every {
+                Some(every) => self.align(every),
+                None => self.timers[i].due + delta,
+            };
+        }
+    }
+
+    fn record_tick(&mut self, time: &MidiTime) {
+        // The device's time is more precise, when there is one
+        let us = match time.device {
+            0 => super::misc::nanos_since_start(time.received) as f64 / 1000.0,
+            x => x as f64,
+        };
+        if self.last_tick.is_some_and(|x| time.received.saturating_duration_since(x) > CLOCK_TIMEOUT) {
+            self.recent.clear();
+        }
+        self.last_tick = Some(time.received);
+        self.recent.push_back(us);
+        if self.recent.len() > TEMPO_WINDOW + 1 {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Handles a tick while the transport runs. Returns the beat and bar if
+    /// it starts a beat, and the timers that are due.
+    fn advance(&mut self) -> (Option<(u64, u64)>, Vec<Arc<RegistryKey>>) {
+        let pos = self.ticks;
+        self.now = pos;
+        self.ticks += 1;
+
+        let beat = (pos % TICKS_PER_BEAT == 0).then(|| {
+            let beat = pos / TICKS_PER_BEAT;
+            (beat % self.beats_per_bar + 1, beat / self.beats_per_bar + 1)
+        });
+
+        let mut due = vec![];
+        self.timers.retain_mut(|timer| {
+            if timer.due > pos as f64 {
+                return true;
+            }
+            due.push(timer.f.clone());
+            match timer.every {
+                Some(every) => {
+                    while timer.due <= pos as f64 {
+                        timer.due += every;
+                    }
+                    true
+                },
+                None => false,
+            }
+        });
+
+        (beat, due)
+    }
+}
+
+/// The script's view of the clock, and its timers.
+fn with_clock<T>(l: &mlua::Lua, f: impl FnOnce(&mut Clock) -> T) -> T {
+    super::with_state(l, f)
+}
+
+/// Follows a clock message, calling `on_beat(beat, bar)` and any timers that
+/// are due.
+pub fn handle(l: &mlua::Lua, event: ClockEvent, time: &MidiTime) -> mlua::Result<()> {
+    let (beat, due) = with_clock(l, |clock| {
+        match event {
+            ClockEvent::Tick => {
+                clock.record_tick(time);
+                if clock.running {
+                    return clock.advance();
+                }
+            },
+            ClockEvent::Start => {
+                clock.jump(0);
+                clock.running = true;
+            },
+            ClockEvent::Continue => clock.running = true,
+            ClockEvent::Stop => clock.running = false,
+            ClockEvent::Position(x) => clock.jump(x as u64 * TICKS_PER_SIXTEENTH),
+        }
+        (None, vec![])
+    });
+
+    if let Some((beat, bar)) = beat {
+        if let Ok(on_beat) = l.globals().get::<_, mlua::Function>("on_beat") {
+            on_beat.call::<_, ()>((beat, bar))?;
+        }
+    }
+    for f in due {
+        l.registry_value::<mlua::Function>(&f)?.call::<_, ()>(())?;
+    }
+
+    Ok(())
+}
+
+fn add_timer<'lua>(l: &'lua mlua::Lua, beats: f64, f: mlua::Function, repeat: bool) -> mlua::Result<mlua::Table<'lua>> {
+    if beats <= 0.0 || !beats.is_finite() {
+        return Err(mlua::Error::RuntimeError(format!("can't wait {} beats", beats)));
+    }
+    let ticks = beats * TICKS_PER_BEAT as f64;
+    // Timers only run on ticks, so anything faster would just pile up
+    if repeat && ticks < 1.0 {
+        return Err(mlua::Error::RuntimeError(format!("can't repeat more often than once a tick (1/{} beat)", TICKS_PER_BEAT)));
+    }
+    let f = Arc::new(l.create_registry_value(f)?);
+
+    let id = with_clock(l, |clock| {
+        let id = clock.next_timer;
+        clock.next_timer += 1;
+        let (every, due) = match repeat {
+            true => (Some(ticks), clock.align(ticks)),
+            false => (None, clock.now as f64 + ticks),
+        };
+        clock.timers.push(Timer { id, f, every, due });
+        id
+    });
+
+    let tab = l.create_table()?;
+    tab.set("cancel", l.create_function(move |l, _: ()| {
+        with_clock(l, |clock| clock.timers.retain(|x| x.id != id));
+        Ok(())
+    })?)?;
+    Ok(tab)
+}
+
+/// The `clock` table for following MIDI clock, and `timer` for running
+/// things in musical time.
+pub struct Clocks;
+impl ApiProvider for Clocks {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        super::init_state(l, Clock::default());
+        let tab = l.create_table()?;
+
+        // nil until clock comes in, and again once it's stopped for a second
+        tab.set("bpm", l.create_function(|l, _: ()| {
+            Ok(with_clock(l, |clock| clock.bpm()))
+        })?)?;
+
+        tab.set("running", l.create_function(|l, _: ()| {
+            Ok(with_clock(l, |clock| clock.running))
+        })?)?;
+
+        // In beats from the start of the song
+        tab.set("position", l.create_function(|l, _: ()| {
+            Ok(with_clock(l, |clock| clock.now as f64 / TICKS_PER_BEAT as f64))
+        })?)?;
+
+        tab.set("set_beats_per_bar", l.create_function(|l, (beats,): (u64,)| {
+            if beats == 0 {
+                return Err(mlua::Error::RuntimeError("a bar needs at least one beat".into()));
+            }
+            with_clock(l, |clock| clock.beats_per_bar = beats);
+            Ok(())
+        })?)?;
+
+        l.globals().set("clock", tab)?;
+
+        let timer = l.create_table()?;
+
+        // Calls f on every multiple of `beats` while the transport runs, so
+        // every_beats(1, f) is on the beat and every_beats(0.5, f) on eighths
+        timer.set("every_beats", l.create_function(|l, (beats, f): (f64, mlua::Function)| {
+            add_timer(l, beats, f, true)
+        })?)?;
+
+        timer.set("after_beats", l.create_function(|l, (beats, f): (f64, mlua::Function)| {
+            add_timer(l, beats, f, false)
+        })?)?;
+
+        l.globals().set("timer", timer)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Weak, atomic::{AtomicU32, Ordering}},
+    time::{Duration, Instant},
+};
+use input_linux::{EvdevHandle, EventKind, InputEvent, Key, sys::input_event};
+use parking_lot::Mutex;
+
+use crate::{Message, MessageSender, sandbox, script};
+use super::{ApiProvider, midi::OFFLINE};
+
+/// Range to assume for an axis when the device doesn't say.
+const DEFAULT_RANGE: (i32, i32) = (-32768, 32767);
+
+/// How long reading a device waits for events before checking whether
+/// anything still wants them.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Minimum and maximum of each absolute axis on a device.
+type AxisRanges = HashMap<u16, (i32, i32)>;
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+lazy_static::lazy_static! {
+    /// Devices that are open, by id. Scripts opening the same one share it,
+    /// so reloading a script doesn't open it twice, and unplugged ones are
+    /// picked back up when they come back. A device is closed once no
+    /// script has it open.
+    static ref OPENED: Mutex<HashMap<u32, Weak<Mutex<Opened>>>> = Mutex::new(HashMap::new());
+}
+
+struct Opened {
+    path: PathBuf,
+    name: String,
+    /// Where it's plugged in and its serial number, when the driver says.
+    phys: Option<String>,
+    uniq: Option<String>,
+    grab: bool,
+    keyboard: bool,
+    /// Cleared when it's unplugged.
+    connected: bool,
+    ranges: AxisRanges,
+    /// Shared with the thread reading it, `None` when input is simulated.
+    handle: Option<Arc<EvdevHandle<File>>>,
+}
+
+impl Drop for Opened {
+    fn drop(&mut self) {
+        // The reader thread closes it soon, but whatever wants it next
+        // shouldn't have to wait for that
+        if let (true, Some(handle)) = (self.grab, &self.handle) {
+            let _ = handle.grab(false);
+        }
+    }
+}
+
+/// What the script has open, by id.
+#[derive(Default)]
+struct Devices(HashMap<u32, Arc<Mutex<Opened>>>);
+
+/// Every device that's still open, by id.
+fn opened() -> Vec<(u32, Arc<Mutex<Opened>>)> {
+    let mut opened = OPENED.lock();
+    opened.retain(|_, x| x.strong_count() > 0);
+    opened.iter().filter_map(|(id, x)| Some((*id, x.upgrade()?))).collect()
+}
+
+impl Opened {
+    /// How likely a device that's turned up with the same name is this one
+    /// back again, or `None` if it can't be. Serial numbers have to agree
+    /// when both have one, and the same port beats just the same name.
+    fn likeness(&self, phys: &Option<String>, uniq: &Option<String>) -> Option<u8> {
+        match (&self.uniq, uniq) {
+            (Some(a), Some(b)) if a == b => Some(2),
+            (Some(_), Some(_)) => None,
+            _ => Some((self.phys.is_some() && self.phys == *phys) as u8),
+        }
+    }
+}
+
+/// Whether the script opened the device, so should get its events.
+pub fn opened_by(l: &mlua::Lua, device: u32) -> bool {
+    super::with_state(l, |x: &mut Devices| x.0.contains_key(&device))
+}
+
+/// Maps an absolute axis value onto 0..1 using the range the device reported.
+pub fn normalise(device: u32, code: u16, value: i32) -> f32 {
+    let dev = OPENED.lock().get(&device).and_then(|x| x.upgrade());
+    let (min, max) = dev
+        .and_then(|x| x.lock().ranges.get(&code).copied())
+        .unwrap_or(DEFAULT_RANGE);
+    if max <= min {
+        return 0.0;
+    }
+
+    ((value - min) as f32 / (max - min) as f32).clamp(0.0, 1.0)
+}
+
+fn open_device(path: &Path) -> std::io::Result<EvdevHandle<File>> {
+    let fd = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(path)?;
+
+    Ok(EvdevHandle::new(fd))
+}
+
+/// The name of the device at a path, if it can be opened.
+pub fn probe(path: &Path) -> Option<String> {
+    open_device(path).ok().map(|x| device_name(&x))
+}
+
+/// Id of the device opened from a path, if there is one.
+pub fn opened_id(path: &Path) -> Option<u32> {
+    opened().into_iter().find(|(_, x)| x.lock().path == path).map(|(id, _)| id)
+}
+
+/// Anything with letter keys, which the sandbox won't open since reading
+/// it would be a keylogger.
+fn is_keyboard(handle: &EvdevHandle<File>) -> bool {
+    handle.key_bits().is_ok_and(|x| [Key::A, Key::Z, Key::Space].iter().any(|k| x.get(*k)))
+}
+
+fn axis_ranges(handle: &EvdevHandle<File>) -> AxisRanges {
+    let mut ranges = HashMap::new();
+    if let Ok(bits) = handle.absolute_bits() {
+        for axis in bits.iter() {
+            if let Ok(info) = handle.absolute_info(axis) {
+                ranges.insert(axis as u16, (info.minimum, info.maximum));
+            }
+        }
+    }
+    ranges
+}
+
+/// Picks a device back up if it was opened before and then unplugged, so it
+/// keeps its id. Returns the id.
+pub fn reconnect(path: &Path, sender: MessageSender) -> Option<u32> {
+    let handle = open_device(path).ok()?;
+    let name = device_name(&handle);
+    let (phys, uniq) = (device_string(handle.physical_location()), device_string(handle.unique_id()));
+
+    // Two of the same controller have the same name, so which one this is
+    // goes by serial number or port where there are any
+    let (_, id, dev) = opened().into_iter()
+        .filter_map(|(id, x)| {
+            let score = {
+                let dev = x.lock();
+                if dev.connected || dev.name != name {
+                    return None;
+                }
+                dev.likeness(&phys, &uniq)?
+            };
+            Some((score, id, x))
+        })
+        .max_by_key(|(score, ..)| *score)?;
+
+    let handle = Arc::new(handle);
+    {
+        let mut dev = dev.lock();
+        if dev.grab {
+            if let Err(e) = handle.grab(true) {
+                warn!("Could not grab {} again: {}", name, e);
+            }
+        }
+        dev.ranges = axis_ranges(&handle);
+        dev.path = path.to_path_buf();
+        dev.connected = true;
+        dev.handle = Some(handle.clone());
+    }
+
+    info!("Reading input from {} ({:?}) again", name, path);
+    let dev = Arc::downgrade(&dev);
+    std::thread::spawn(move || read_events(id, name, handle, dev, sender));
+    Some(id)
+}
+
+/// One of the strings a device reports, unless it's empty.
+fn device_string(x: std::io::Result<Vec<u8>>) -> Option<String> {
+    let x = String::from_utf8_lossy(&x.ok()?).trim_end_matches('\0').to_string();
+    (!x.is_empty()).then_some(x)
+}
+
+fn device_name(handle: &EvdevHandle<File>) -> String {
+    device_string(handle.device_name()).unwrap_or_else(|| "unknown device".into())
+}
+
+/// Every input device we're allowed to open, as (path, name).
+fn list_devices() -> Vec<(PathBuf, String)> {
+    let mut paths = match std::fs::read_dir("/dev/input") {
+        Ok(x) => x.filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.file_name().is_some_and(|x| x.to_string_lossy().starts_with("event")))
+            .collect::<Vec<_>>(),
+        Err(_) => vec![],
+    };
+    paths.sort_by_key(|x| {
+        let name = x.file_name().unwrap_or_default().to_string_lossy().to_string();
+        name.trim_start_matches("event").parse::<u32>().unwrap_or(u32::MAX)
+    });
+
+    paths.into_iter()
+        .filter_map(|path| {
+            let handle = open_device(&path).ok()?;
+            let name = device_name(&handle);
+            Some((path, name))
+        })
+        .collect()
+}
+
+/// Whether a device has something to read, waiting a little for it.
+fn readable(handle: &EvdevHandle<File>) -> bool {
+    let mut fd = libc::pollfd { fd: handle.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut fd, 1, READ_TIMEOUT.as_millis() as i32) > 0 }
+}
+
+/// Sends key and axis events from a device to the dispatcher until it goes
+/// away, or nothing has it open any more.
+fn read_events(id: u32, name: String, handle: Arc<EvdevHandle<File>>, dev: Weak<Mutex<Opened>>, sender: MessageSender) {
+    let mut buf: [input_event; 32] = unsafe { std::mem::zeroed() };
+
+    loop {
+        if dev.strong_count() == 0 {
+            debug!("Closed {}", name);
+            break;
+        }
+        if !readable(&handle) {
+            continue;
+        }
+        let n = match handle.read(&mut buf) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Stopped reading from {}: {}", name, e);
+                if let Some(dev) = dev.upgrade() {
+                    dev.lock().connected = false;
+                }
+                break;
+            },
+        };
+
+        for raw in &buf[..n] {
+            let ev = match InputEvent::from_raw(raw) {
+                Ok(x) => *x,
+                Err(_) => continue,
+            };
+            if !matches!(ev.kind, EventKind::Key | EventKind::Absolute) {
+                continue;
+            }
+            if sender.send(Message::Evdev(id, ev, Instant::now())).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn create_device_table<'lua>(l: &'lua mlua::Lua, id: u32, dev: Arc<Mutex<Opened>>) -> mlua::Result<mlua::Table<'lua>> {
+    let tab = l.create_table()?;
+    tab.set("id", id)?;
+    {
+        let dev = dev.lock();
+        tab.set("name", dev.name.as_str())?;
+        tab.set("path", dev.path.to_string_lossy().to_string())?;
+    }
+    super::with_state(l, |x: &mut Devices| x.0.insert(id, dev));
+    Ok(tab)
+}
+
+pub struct Evdev;
+impl ApiProvider for Evdev {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        super::init_state(l, Devices::default());
+        let tab = l.create_table()?;
+
+        tab.set("list", l.create_function(|l, _: ()| {
+            let out = l.create_table()?;
+            for (i, (path, name)) in list_devices().into_iter().enumerate() {
+                let dev = l.create_table()?;
+                dev.set("path", path.to_string_lossy().to_string())?;
+                dev.set("name", name)?;
+                out.set(i + 1, dev)?;
+            }
+            Ok(out)
+        })?)?;
+
+        // Takes either a path under /dev/input or part of a device's name.
+        // With `grab = true` nothing else sees the device's events.
+        tab.set("open", l.create_function(|l, (which, opts): (String, Option<mlua::Table>)| {
+            let grab = match &opts {
+                Some(opts) => opts.get::<_, Option<bool>>("grab")?.unwrap_or(false),
+                None => false,
+            };
+            if OFFLINE.load(Ordering::Relaxed) {
+                let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                info!("Input is simulated, not opening {}", which);
+                let dev = Arc::new(Mutex::new(Opened {
+                    path: PathBuf::from(&which), name: which, phys: None, uniq: None,
+                    grab, keyboard: false, connected: true, ranges: HashMap::new(), handle: None,
+                }));
+                OPENED.lock().insert(id, Arc::downgrade(&dev));
+                return create_device_table(l, id, dev);
+            }
+
+            let path = if which.starts_with('/') {
+                Some(PathBuf::from(&which))
+            } else {
+                list_devices().into_iter()
+                    .find(|(_, name)| name.contains(which.as_str()))
+                    .map(|(path, _)| path)
+            };
+            {
+                // Already open, or opened before and unplugged since, in
+                // which case it gets picked up again when it's back
+                let found = opened().into_iter().find(|(_, x)| {
+                    let x = x.lock();
+                    match &path {
+                        Some(path) => x.path == *path,
+                        None => !x.connected && x.name.contains(which.as_str()),
+                    }
+                });
+                if let Some((id, dev)) = found {
+                    let (keyboard, name) = {
+                        let dev = dev.lock();
+                        (dev.keyboard, dev.name.clone())
+                    };
+                    if sandbox::sandboxed(l) && keyboard {
+                        return Err(mlua::Error::RuntimeError(format!("can't open {} from the sandbox, it's a keyboard", name)));
+                    }
+                    return create_device_table(l, id, dev);
+                }
+            }
+            let path = path.ok_or_else(|| mlua::Error::RuntimeError(format!("no input device called '{}'", which)))?;
+
+            let handle = open_device(&path)
+                .map_err(|e| mlua::Error::RuntimeError(format!("could not open {:?}: {}", path, e)))?;
+            let name = device_name(&handle);
+            let ranges = axis_ranges(&handle);
+
+            let keyboard = is_keyboard(&handle);
+            let (phys, uniq) = (device_string(handle.physical_location()), device_string(handle.unique_id()));
+            if sandbox::sandboxed(l) && keyboard {
+                return Err(mlua::Error::RuntimeError(format!("can't open {} from the sandbox, it's a keyboard", name)));
+            }
+
+            if grab {
+                handle.grab(true)
+                    .map_err(|e| mlua::Error::RuntimeError(format!("could not grab {}: {}", name, e)))?;
+            }
+
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            info!("Reading input from {} ({:?})", name, path);
+            let handle = Arc::new(handle);
+            let dev = Arc::new(Mutex::new(Opened {
+                path, name: name.clone(), phys, uniq, grab, keyboard, connected: true, ranges, handle: Some(handle.clone()),
+            }));
+            OPENED.lock().insert(id, Arc::downgrade(&dev));
+            {
+                let dev = Arc::downgrade(&dev);
+                let sender = script::sender(l);
+                std::thread::spawn(move || read_events(id, name, handle, dev, sender));
+            }
+
+            create_device_table(l, id, dev)
+        })?)?;
+
+        l.globals().set("evdev", tab)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{output::RecordingBackend, sandbox::Limits, script::Script};
+    use super::*;
+
+    fn open_count() -> usize {
+        opened().len()
+    }
+
+    #[test]
+    fn disable_closes_devices() {
+        OFFLINE.store(true, Ordering::Relaxed);
+        let mut script = Script::new("test".into(), None, Limits::default());
+        let (sender, _) = tokio::sync::mpsc::unbounded_channel();
+        let backend = Arc::new(RecordingBackend::new(false));
+        let before = open_count();
+
+        for _ in 0..2 {
+            script.start(backend.clone(), sender.clone()).unwrap();
+            let id = script.lua.as_ref().unwrap().load(r#"return evdev.open("pad", { grab = true }).id"#).eval::<u32>().unwrap();
+            assert!(opened_by(script.lua.as_ref().unwrap(), id));
+            assert_eq!(open_count(), before + 1);
+
+            script.stop();
+            assert_eq!(open_count(), before);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use mlua::UserData;
+
+use crate::filter::{Filter, FilterKind};
+
+use super::ApiProvider;
+
+pub struct LuaFilter(pub Filter);
+impl UserData for LuaFilter {
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("update", |_l, this, (value,): (f32,)| {
+            Ok(this.0.update(value))
+        });
+
+        methods.add_method("value", |_l, this, _: ()| {
+            Ok(this.0.value())
+        });
+
+        methods.add_method_mut("reset", |_l, this, _: ()| {
+            this.0.reset();
+            Ok(())
+        });
+    }
+}
+
+pub struct Filters;
+impl ApiProvider for Filters {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        let tab = l.create_table()?;
+
+        tab.set("ema", l.create_function(|_l, (tau,): (f32,)| {
+            Ok(LuaFilter(Filter::new(FilterKind::Ema { tau })))
+        })?)?;
+
+        tab.set("one_euro", l.create_function(|_l, (min_cutoff, beta, d_cutoff): (f32, Option<f32>, Option<f32>)| {
+            Ok(LuaFilter(Filter::new(FilterKind::OneEuro {
+                min_cutoff,
+                beta: beta.unwrap_or(0.0),
+                d_cutoff: d_cutoff.unwrap_or(1.0),
+            })))
+        })?)?;
+
+        tab.set("slew", l.create_function(|_l, (rate,): (f32,)| {
+            if !(rate > 0.0 && rate.is_finite()) {
+                return Err(mlua::Error::RuntimeError(format!("slew rate must be a positive number, not {}", rate)));
+            }
+            Ok(LuaFilter(Filter::new(FilterKind::Slew { rate })))
+        })?)?;
+
+        tab.set("hysteresis", l.create_function(|_l, (threshold,): (f32,)| {
+            Ok(LuaFilter(Filter::new(FilterKind::Hysteresis { threshold })))
+        })?)?;
+
+        l.globals().set("filter", tab)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use crate::focus;
+use super::{ApiProvider, map};
+
+/// A layer that's active while a matching application has focus.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    /// Matched against the whole app id, ignoring case.
+    pub app: Option<String>,
+    /// Matched against any part of the title, ignoring case.
+    pub title: Option<String>,
+    pub layer: String,
+}
+
+impl Profile {
+    fn matches(&self, app_id: &str, title: &str) -> bool {
+        let app = self.app.as_ref().map_or(true, |x| x.eq_ignore_ascii_case(app_id));
+        let title = self.title.as_ref().map_or(true, |x| title.to_lowercase().contains(&x.to_lowercase()));
+        app && title
+    }
+}
+
+/// The script's profiles.
+#[derive(Default)]
+struct Profiles(Vec<Profile>);
+
+pub fn add_profile(l: &mlua::Lua, profile: Profile) {
+    super::with_state(l, |x: &mut Profiles| x.0.push(profile));
+}
+
+/// Turns on the layers of profiles matching the newly focused application,
+/// and turns off the rest.
+pub fn switch(l: &mlua::Lua, app_id: &str, title: &str) {
+    let profiles = super::with_state(l, |x: &mut Profiles| x.0.clone());
+    let (on, off): (Vec<_>, Vec<_>) = profiles.iter().partition(|x| x.matches(app_id, title));
+
+    for p in off {
+        map::set_active(l, &p.layer, false);
+    }
+    for p in on {
+        debug!("Switching to layer '{}' for {}", p.layer, app_id);
+        map::set_active(l, &p.layer, true);
+    }
+}
+
+pub struct Focus;
+impl ApiProvider for Focus {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        super::init_state(l, Profiles::default());
+        let tab = l.create_table()?;
+
+        // Either an app id, or a table of app and/or title
+        tab.set("profile", l.create_function(|l, (which, layer): (mlua::Value, String)| {
+            let (app, title) = match which {
+                mlua::Value::String(x) => (Some(x.to_str()?.to_string()), None),
+                mlua::Value::Table(x) => (x.get("app")?, x.get("title")?),
+                x => return Err(mlua::Error::RuntimeError(format!("expected an app id or a table, got {}", x.type_name()))),
+            };
+            let profile = Profile { app, title, layer };
+
+            // Something may already have focus
+            if let Some((app_id, title)) = focus::current() {
+                if profile.matches(&app_id, &title) {
+                    map::set_active(l, &profile.layer, true);
+                }
+            }
+            add_profile(l, profile);
+            Ok(())
+        })?)?;
+
+        // The app id and title of whatever has focus, or nil
+        tab.set("current", l.create_function(|_l, _: ()| {
+            Ok(focus::current().unzip())
+        })?)?;
+
+        l.globals().set("focus", tab)?;
+
+        Ok(())
+    }
+}
@@ -1,6 +1,5 @@
-use std::{sync::Arc, fs::File};
+use std::{sync::{Arc, Weak}, collections::HashMap};
 use input_linux::{
-    UInputHandle,
     EventKind,
     Key,
     AbsoluteAxis,
@@ -15,7 +14,8 @@ use input_linux::{
     SynchronizeKind, AbsoluteEvent
 };
 use parking_lot::Mutex;
-use super::ApiProvider;
+use crate::{stick::StickConfig, filter::Filter, modifier::{Modifier, Effect}, output::{OutputBackend, OutputDevice}};
+use super::{ApiProvider, filter::LuaFilter, macros::{RecorderHandle, MacroEvent}};
 
 fn i32_to_key(a: i32) -> Key {
     match a {
@@ -48,13 +48,300 @@ fn i32_to_absaxis(a: i32) -> AbsoluteAxis {
     }
 }
 
+#[derive(Default, Clone, serde::Serialize)]
+pub struct PadState {
+    buttons: HashMap<i32, bool>,
+    axes: HashMap<i32, f32>,
+}
+
+const BUTTONS: [Key; 13] = [
+    Key::ButtonSouth,
+    Key::ButtonEast,
+    Key::ButtonNorth,
+    Key::ButtonWest,
+    Key::ButtonStart,
+    Key::ButtonSelect,
+    Key::ButtonTL,
+    Key::ButtonTR,
+    Key::ButtonTL2,
+    Key::ButtonTR2,
+    Key::ButtonThumbl,
+    Key::ButtonThumbr,
+    Key::ButtonMode,
+];
+
+const AXES: [AbsoluteAxis; 8] = [
+    AbsoluteAxis::X,
+    AbsoluteAxis::Y,
+    AbsoluteAxis::RX,
+    AbsoluteAxis::RY,
+    AbsoluteAxis::Hat2Y,
+    AbsoluteAxis::Hat2X,
+    AbsoluteAxis::Hat0X,
+    AbsoluteAxis::Hat0Y,
+];
+
+const STICKS: [(AbsoluteAxis, AbsoluteAxis); 2] = [
+    (AbsoluteAxis::X, AbsoluteAxis::Y),
+    (AbsoluteAxis::RX, AbsoluteAxis::RY),
+];
+
+fn axis_to_stick(a: i32) -> Option<usize> {
+    STICKS.iter().position(|(x, y)| *x as i32 == a || *y as i32 == a)
+}
+
+pub struct VirtualPad {
+    device: Box<dyn OutputDevice>,
+    // Shadow of what the script has sent, so it can be read back
+    state: PadState,
+    sticks: [Option<StickConfig>; 2],
+    filters: HashMap<i32, Filter>,
+    modifiers: HashMap<i32, Modifier>,
+    // Modifier callbacks, run from `tick` once the pad is unlocked
+    pending_calls: Vec<(Arc<mlua::RegistryKey>, i32)>,
+    pub recorder: Option<RecorderHandle>,
+}
+
+impl VirtualPad {
+    fn new(device: Box<dyn OutputDevice>) -> Self {
+        let mut state = PadState::default();
+        for i in BUTTONS {
+            state.buttons.insert(i as i32, false);
+        }
+        for i in AXES {
+            state.axes.insert(i as i32, 0.0);
+        }
+
+        Self {
+            device,
+            state,
+            sticks: [None, None],
+            filters: HashMap::new(),
+            modifiers: HashMap::new(),
+            pending_calls: vec![],
+            recorder: None,
+        }
+    }
+
+    fn write(&self, events: &[InputEvent]) -> mlua::Result<()> {
+        const ZERO: EventTime = EventTime::new(0, 0);
+        let mut raw = events.iter().map(|x| *x.as_raw()).collect::<Vec<_>>();
+        raw.push(*InputEvent::from(SynchronizeEvent::new(ZERO, SynchronizeKind::Report, 0)).as_raw());
+        self.device.write(&raw)?;
+
+        Ok(())
+    }
+
+    fn write_button(&self, key: i32, state: bool) -> mlua::Result<()> {
+        const ZERO: EventTime = EventTime::new(0, 0);
+        self.write(&[
+            InputEvent::from(KeyEvent::new(ZERO, i32_to_key(key), match state {
+                true => KeyState::PRESSED,
+                false => KeyState::RELEASED
+            })),
+        ])
+    }
+
+    fn apply(&mut self, effects: Vec<Effect>) -> mlua::Result<()> {
+        for fx in effects {
+            match fx {
+                Effect::Set(key, state) => self.write_button(key, state)?,
+                Effect::Call(f, key) => self.pending_calls.push((f, key)),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_button(&mut self, key: i32, state: bool) -> mlua::Result<()> {
+        self.state.buttons.insert(key, state);
+        if let Some(rec) = &self.recorder {
+            rec.lock().push(MacroEvent::Button { code: key, pressed: state });
+        }
+
+        match self.modifiers.get_mut(&key) {
+            Some(m) => {
+                let fx = m.press(key, state, super::misc::now());
+                self.apply(fx)
+            },
+            None => self.write_button(key, state),
+        }
+    }
+
+    fn set_modifier(&mut self, key: i32, modifier: Option<Modifier>) -> mlua::Result<()> {
+        if let Some(mut old) = self.modifiers.remove(&key) {
+            let fx = old.release(key);
+            self.apply(fx)?;
+        }
+
+        let held = self.state.buttons.get(&key).copied().unwrap_or(false);
+        match modifier {
+            Some(mut m) => {
+                if held {
+                    let fx = m.press(key, true, super::misc::now());
+                    self.apply(fx)?;
+                }
+                self.modifiers.insert(key, m);
+            },
+            None if held => self.write_button(key, true)?,
+            None => {},
+        }
+
+        Ok(())
+    }
+
+    /// Takes the new value, which still has to be flushed out.
+    fn set_axis(&mut self, axis: i32, value: f32) {
+        self.state.axes.insert(axis, value);
+        if let Some(rec) = &self.recorder {
+            rec.lock().push(MacroEvent::Axis { code: axis, value });
+        }
+        if let Some(f) = self.filters.get_mut(&axis) {
+            f.update(value);
+        }
+    }
+
+    fn set_filter(&mut self, axis: i32, filter: Option<Filter>) {
+        match filter {
+            Some(mut f) => {
+                // Start from where the axis currently is, rather than jumping
+                f.seed(self.output(axis));
+                self.filters.insert(axis, f);
+            },
+            None => {
+                self.filters.remove(&axis);
+            },
+        }
+    }
+
+    /// The value of an axis after filtering, before stick processing.
+    fn output(&self, axis: i32) -> f32 {
+        match self.filters.get(&axis).and_then(|f| f.value()) {
+            Some(x) => x,
+            None => self.state.axes.get(&axis).copied().unwrap_or(0.0),
+        }
+    }
+
+    /// Returns the axes that filters moved, which need flushing.
+    fn tick(&mut self) -> mlua::Result<Vec<i32>> {
+        let mut changed = vec![];
+        for (axis, f) in self.filters.iter_mut() {
+            let old = f.value().unwrap_or(0.0);
+            let target = self.state.axes.get(axis).copied().unwrap_or(0.0);
+            let new = f.update(target);
+            if (new - old).abs() > 1.0 / 32768.0 {
+                changed.push(*axis);
+            }
+        }
+
+        let now = super::misc::now();
+        let mut fx = vec![];
+        for (key, m) in self.modifiers.iter_mut() {
+            fx.extend(m.tick(*key, now));
+        }
+        self.apply(fx)?;
+
+        Ok(changed)
+    }
+}
+
+fn to_abs(axis: AbsoluteAxis, value: f32) -> InputEvent {
+    const ZERO: EventTime = EventTime::new(0, 0);
+    let axis_value: i32 = (32768.0 * value).round() as i32;
+    InputEvent::from(AbsoluteEvent::new(ZERO, axis, axis_value))
+}
+
+#[derive(Clone)]
+pub struct PadHandle(pub Arc<Mutex<VirtualPad>>);
+impl mlua::UserData for PadHandle {}
+
+impl PadHandle {
+    pub fn set_axis(&self, l: &mlua::Lua, axis: i32, value: f32) -> mlua::Result<()> {
+        self.0.lock().set_axis(axis, value);
+        self.flush_axis(l, axis)
+    }
+
+    /// Sends an axis out. Stick curves can be Lua functions that look at the
+    /// pad themselves, so sticks are processed with the pad unlocked.
+    fn flush_axis(&self, l: &mlua::Lua, axis: i32) -> mlua::Result<()> {
+        let (i, cfg, x, y) = {
+            let pad = self.0.lock();
+            let stick = axis_to_stick(axis).and_then(|i| pad.sticks[i].clone().map(|cfg| (i, cfg)));
+            match stick {
+                Some((i, cfg)) => {
+                    let (ax, ay) = STICKS[i];
+                    (i, cfg, pad.output(ax as i32), pad.output(ay as i32))
+                },
+                None => return pad.write(&[to_abs(i32_to_absaxis(axis), pad.output(axis))]),
+            }
+        };
+
+        // Sticks are processed as a pair, so both axes get re-sent
+        let (ax, ay) = STICKS[i];
+        let (x, y) = cfg.process(l, x, y)?;
+        self.0.lock().write(&[to_abs(ax, x), to_abs(ay, y)])
+    }
+}
+
+/// The script's gamepads, in the order it created them.
+#[derive(Default)]
+struct Pads(Vec<Weak<Mutex<VirtualPad>>>);
+
+fn live_pads(l: &mlua::Lua) -> Vec<Arc<Mutex<VirtualPad>>> {
+    super::with_state(l, |x: &mut Pads| {
+        x.0.retain(|x| x.strong_count() > 0);
+        x.0.iter().filter_map(|x| x.upgrade()).collect()
+    })
+}
+
+/// A live gamepad by its position among the scripts', counting from 1,
+/// along with the Lua state of the script that made it.
+pub fn get<'a>(scripts: impl Iterator<Item = &'a mlua::Lua>, idx: usize) -> Option<(&'a mlua::Lua, PadHandle)> {
+    scripts
+        .flat_map(|l| live_pads(l).into_iter().map(move |x| (l, PadHandle(x))))
+        .nth(idx.checked_sub(1)?)
+}
+
+/// What every live gamepad has been told, in the order they were created.
+pub fn states<'a>(scripts: impl Iterator<Item = &'a mlua::Lua>) -> Vec<PadState> {
+    scripts
+        .flat_map(live_pads)
+        .map(|x| x.lock().state.clone())
+        .collect()
+}
+
+/// Advances time-based processing (filters, button modifiers) on every
+/// live gamepad the script created.
+pub fn tick(l: &mlua::Lua) -> mlua::Result<()> {
+    let pads = live_pads(l);
+
+    for pad in pads {
+        let (changed, calls) = {
+            let mut pad = pad.lock();
+            let changed = pad.tick()?;
+            (changed, std::mem::take(&mut pad.pending_calls))
+        };
+
+        let pad = PadHandle(pad);
+        for axis in changed {
+            pad.flush_axis(l, axis)?;
+        }
+
+        for (f, key) in calls {
+            l.registry_value::<mlua::Function>(&f)?.call::<_, ()>((key,))?;
+        }
+    }
+
+    Ok(())
+}
+
 pub struct Gamepad;
 impl ApiProvider for Gamepad {
-    type Arguments = (UInputHandle<File>,);
+    type Arguments = (Arc<dyn OutputBackend>,);
 
     fn register_api(l: &mlua::Lua, args: Self::Arguments) -> anyhow::Result<()> {
-        let (uinput,) = args;
-        let outest = Arc::new(Mutex::new(uinput));
+        let (backend,) = args;
+        super::init_state(l, Pads::default());
 
         let tab = l.create_table()?;
 
@@ -112,10 +399,13 @@ impl ApiProvider for Gamepad {
         tab.set("AXIS_DPAD_X", AbsoluteAxis::Hat0X as i32)?;
         tab.set("AXIS_DPAD_Y", AbsoluteAxis::Hat0Y as i32)?;
 
+        tab.set("STICK_LEFT", "left")?;
+        tab.set("STICK_RIGHT", "right")?;
+
         {
-            let outer = outest.clone();
+            let backend = backend.clone();
             tab.set("create", l.create_function(move |l, (id,): (Option<String>,)| {
-                let uinput = outer.lock();
+                let uinput = backend.open().map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
 
                 // https://docs.kernel.org/input/gamepad.html
 
@@ -184,7 +474,7 @@ impl ApiProvider for Gamepad {
                     ..JOYSTICK
                 };
 
-                uinput.create(&input_id, device_name, 0, &[
+                uinput.create(&input_id, device_name, &[
                     AbsoluteInfoSetup {
                         axis: AbsoluteAxis::X,
                         info: JOYSTICK,
@@ -219,39 +509,110 @@ impl ApiProvider for Gamepad {
                     },
                 ])?;
 
+                let pad = Arc::new(Mutex::new(VirtualPad::new(uinput)));
+                super::with_state(l, |x: &mut Pads| x.0.push(Arc::downgrade(&pad)));
+
                 let tab = l.create_table()?;
+                tab.set("_handle", PadHandle(pad.clone()))?;
 
                 {
-                    let uinput = outest.clone();
+                    let pad = pad.clone();
                     tab.set("button", l.create_function(move |_l, (key, state): (i32, bool)| {
-                        let ui = uinput.lock();
-                        const ZERO: EventTime = EventTime::new(0, 0);
-                        let event = [
-                            *InputEvent::from(KeyEvent::new(ZERO, i32_to_key(key), match state {
-                                true => KeyState::PRESSED,
-                                false => KeyState::RELEASED
-                            })).as_raw(),
-                            *InputEvent::from(SynchronizeEvent::new(ZERO, SynchronizeKind::Report, 0)).as_raw(),
-                        ];
-                        ui.write(&event)?;
-    
-                        Ok(())
+                        pad.lock().set_button(key, state)
+                    })?)?;
+                }
+
+                {
+                    let pad = PadHandle(pad.clone());
+                    tab.set("axis", l.create_function(move |l, (axis, value): (i32, f32)| {
+                        pad.set_axis(l, axis, value)
+                    })?)?;
+                }
+
+                {
+                    let pad = PadHandle(pad.clone());
+                    tab.set("configure_stick", l.create_function(move |l, (stick, cfg): (String, Option<mlua::Table>)| {
+                        let idx = match stick.as_str() {
+                            "left" => 0,
+                            "right" => 1,
+                            x => return Err(mlua::Error::RuntimeError(format!("unknown stick '{}'", x))),
+                        };
+                        let cfg = match cfg {
+                            Some(x) => Some(StickConfig::from_table(l, x)?),
+                            None => None,
+                        };
+
+                        // Re-send the current position through the new settings.
+                        // It isn't a move, so a macro being recorded doesn't get it
+                        let (x, y) = STICKS[idx];
+                        let plain = cfg.is_none();
+                        pad.0.lock().sticks[idx] = cfg;
+                        pad.flush_axis(l, x as i32)?;
+                        match plain {
+                            true => pad.flush_axis(l, y as i32),
+                            false => Ok(()),
+                        }
+                    })?)?;
+                }
+
+                {
+                    let pad = PadHandle(pad.clone());
+                    tab.set("set_filter", l.create_function(move |l, (axis, filter): (i32, Option<mlua::AnyUserData>)| {
+                        let filter = match filter {
+                            Some(x) => {
+                                let mut f = x.borrow::<LuaFilter>()?.0.clone();
+                                f.reset();
+                                Some(f)
+                            },
+                            None => None,
+                        };
+                        pad.0.lock().set_filter(axis, filter);
+                        pad.flush_axis(l, axis)
                     })?)?;
                 }
 
                 {
-                    let uinput = outest.clone();
-                    tab.set("axis", l.create_function(move |_l, (axis, value): (i32, f32)| {
-                        let ui = uinput.lock();
-                        const ZERO: EventTime = EventTime::new(0, 0);
-                        let axis_value: i32 = (32768.0 * value).round() as i32;
-                        let event = [
-                            *InputEvent::from(AbsoluteEvent::new(ZERO, i32_to_absaxis(axis), axis_value)).as_raw(),
-                            *InputEvent::from(SynchronizeEvent::new(ZERO, SynchronizeKind::Report, 0)).as_raw(),
-                        ];
-                        ui.write(&event)?;
-
-                        Ok(())
+                    let pad = pad.clone();
+                    tab.set("set_modifier", l.create_function(move |l, (key, opts): (i32, Option<mlua::Table>)| {
+                        let modifier = match opts {
+                            Some(x) => Some(Modifier::from_table(l, key, x)?),
+                            None => None,
+                        };
+                        pad.lock().set_modifier(key, modifier)
+                    })?)?;
+                }
+
+                {
+                    let pad = pad.clone();
+                    tab.set("get_button", l.create_function(move |_l, (key,): (i32,)| {
+                        Ok(pad.lock().state.buttons.get(&key).copied().unwrap_or(false))
+                    })?)?;
+                }
+
+                {
+                    let pad = pad.clone();
+                    tab.set("get_axis", l.create_function(move |_l, (axis,): (i32,)| {
+                        Ok(pad.lock().state.axes.get(&axis).copied().unwrap_or(0.0))
+                    })?)?;
+                }
+
+                {
+                    let pad = pad.clone();
+                    tab.set("state", l.create_function(move |l, _: ()| {
+                        let pad = pad.lock();
+                        let buttons = l.create_table()?;
+                        for (k, v) in pad.state.buttons.iter() {
+                            buttons.set(*k, *v)?;
+                        }
+                        let axes = l.create_table()?;
+                        for (k, v) in pad.state.axes.iter() {
+                            axes.set(*k, *v)?;
+                        }
+
+                        let tab = l.create_table()?;
+                        tab.set("buttons", buttons)?;
+                        tab.set("axes", axes)?;
+                        Ok(tab)
                     })?)?;
                 }
     
@@ -0,0 +1,169 @@
+use std::{sync::{Arc, Weak}, collections::HashSet};
+use input_linux::{
+    EventKind,
+    Key,
+    InputId,
+    InputEvent,
+    KeyEvent,
+    KeyState,
+    EventTime,
+    SynchronizeEvent,
+    SynchronizeKind,
+};
+use mlua::UserData;
+use parking_lot::Mutex;
+use crate::{output::{OutputBackend, OutputDevice}, sandbox};
+use super::{ApiProvider, macros::{RecorderHandle, MacroEvent}};
+
+/// Mouse, joystick and gamepad buttons, which a keyboard leaves out so it
+/// isn't taken for a joystick: BTN_MISC to BTN_GEAR_UP, the d-pad buttons
+/// and BTN_TRIGGER_HAPPY.
+const BUTTONS: [std::ops::RangeInclusive<u16>; 3] = [0x100..=0x15f, 0x220..=0x223, 0x2c0..=0x2e7];
+
+fn is_key(code: u16) -> bool {
+    code != 0 && !BUTTONS.iter().any(|x| x.contains(&code))
+}
+
+/// The script's keyboards, in the order it created them.
+#[derive(Default)]
+struct Keyboards(Vec<Weak<Mutex<VirtualKeyboard>>>);
+
+/// Keys held on every live keyboard, in the order they were created.
+pub fn pressed_keys<'a>(scripts: impl Iterator<Item = &'a mlua::Lua>) -> Vec<Vec<i32>> {
+    let kbds = scripts.flat_map(|l| super::with_state(l, |x: &mut Keyboards| {
+        x.0.retain(|x| x.strong_count() > 0);
+        x.0.iter().filter_map(|x| x.upgrade()).collect::<Vec<_>>()
+    }));
+    kbds
+        .map(|x| {
+            let mut keys = x.lock().pressed.iter().copied().collect::<Vec<_>>();
+            keys.sort_unstable();
+            keys
+        })
+        .collect()
+}
+
+fn key_name(k: Key) -> String {
+    let name = format!("{:?}", k).to_uppercase();
+    // Key::Num1 is KEY_1 in the kernel headers
+    match name.strip_prefix("NUM") {
+        Some(x) if x.len() == 1 => format!("KEY_{}", x),
+        _ => format!("KEY_{}", name),
+    }
+}
+
+pub struct VirtualKeyboard {
+    device: Box<dyn OutputDevice>,
+    pressed: HashSet<i32>,
+    pub recorder: Option<RecorderHandle>,
+}
+
+impl VirtualKeyboard {
+    pub fn set_key(&mut self, key: i32, state: bool) -> mlua::Result<()> {
+        let k = u16::try_from(key).ok().and_then(|x| Key::from_code(x).ok())
+            .ok_or_else(|| mlua::Error::RuntimeError(format!("unknown key {}", key)))?;
+
+        const ZERO: EventTime = EventTime::new(0, 0);
+        let event = [
+            *InputEvent::from(KeyEvent::new(ZERO, k, match state {
+                true => KeyState::PRESSED,
+                false => KeyState::RELEASED
+            })).as_raw(),
+            *InputEvent::from(SynchronizeEvent::new(ZERO, SynchronizeKind::Report, 0)).as_raw(),
+        ];
+        self.device.write(&event)?;
+
+        if state {
+            self.pressed.insert(key);
+        } else {
+            self.pressed.remove(&key);
+        }
+        if let Some(rec) = &self.recorder {
+            rec.lock().push(MacroEvent::Key { code: key, pressed: state });
+        }
+
+        Ok(())
+    }
+
+    pub fn get_key(&self, key: i32) -> bool {
+        self.pressed.contains(&key)
+    }
+}
+
+#[derive(Clone)]
+pub struct KeyboardHandle(pub Arc<Mutex<VirtualKeyboard>>);
+impl UserData for KeyboardHandle {}
+
+pub struct Keyboard;
+impl ApiProvider for Keyboard {
+    type Arguments = (Arc<dyn OutputBackend>,);
+
+    fn register_api(l: &mlua::Lua, args: Self::Arguments) -> anyhow::Result<()> {
+        let (backend,) = args;
+        super::init_state(l, Keyboards::default());
+        let tab = l.create_table()?;
+
+        for k in Key::iter() {
+            let code = k as u16;
+            if !is_key(code) {
+                continue;
+            }
+            tab.set(key_name(k), code as i32)?;
+        }
+
+        // Typing into other programs is too much for the sandbox
+        tab.set("create", l.create_function(move |l, _: ()| {
+            if sandbox::sandboxed(l) {
+                return Err(mlua::Error::RuntimeError("can't create a keyboard from the sandbox".into()));
+            }
+            let uinput = backend.open().map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+
+            uinput.set_evbit(EventKind::Key)?;
+            for k in Key::iter() {
+                let code = k as u16;
+                if !is_key(code) {
+                    continue;
+                }
+                uinput.set_keybit(k)?;
+            }
+
+            let input_id = InputId {
+                bustype: input_linux::sys::BUS_VIRTUAL,
+                vendor: 0,
+                product: 0,
+                version: 0,
+            };
+            uinput.create(&input_id, b"handcake Virtual Keyboard", &[])?;
+
+            let kbd = KeyboardHandle(Arc::new(Mutex::new(VirtualKeyboard {
+                device: uinput,
+                pressed: HashSet::new(),
+                recorder: None,
+            })));
+            super::with_state(l, |x: &mut Keyboards| x.0.push(Arc::downgrade(&kbd.0)));
+
+            let tab = l.create_table()?;
+            tab.set("_handle", kbd.clone())?;
+
+            {
+                let kbd = kbd.clone();
+                tab.set("key", l.create_function(move |_l, (key, state): (i32, bool)| {
+                    kbd.0.lock().set_key(key, state)
+                })?)?;
+            }
+
+            {
+                let kbd = kbd.clone();
+                tab.set("get_key", l.create_function(move |_l, (key,): (i32,)| {
+                    Ok(kbd.0.lock().get_key(key))
+                })?)?;
+            }
+
+            Ok(tab)
+        })?)?;
+
+        l.globals().set("keyboard", tab)?;
+
+        Ok(())
+    }
+}
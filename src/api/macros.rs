@@ -0,0 +1,290 @@
+use std::{sync::Arc, time::Instant, path::Path};
+use midi_control::MidiMessage;
+use parking_lot::Mutex;
+use serde::{Serialize, Deserialize};
+use crate::{script, util};
+
+use super::{ApiProvider, gamepad::PadHandle, keyboard::KeyboardHandle, midi::{MidiTime, to_message}, misc::now};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MacroEvent {
+    Button { code: i32, pressed: bool },
+    Axis { code: i32, value: f32 },
+    Key { code: i32, pressed: bool },
+    Midi { data: Vec<u8> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Step {
+    /// Seconds since the start of the recording.
+    pub t: f64,
+    #[serde(flatten)]
+    pub event: MacroEvent,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Macro {
+    /// Length of the macro, including any pause after the last event.
+    pub duration: f64,
+    pub steps: Vec<Step>,
+}
+
+impl Macro {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+pub struct Recorder {
+    start: Instant,
+    steps: Vec<Step>,
+}
+
+impl Recorder {
+    pub fn push(&mut self, event: MacroEvent) {
+        self.steps.push(Step {
+            t: now().saturating_duration_since(self.start).as_secs_f64(),
+            event,
+        });
+    }
+}
+
+pub type RecorderHandle = Arc<Mutex<Recorder>>;
+
+/// Where a macro gets recorded from and played back to.
+#[derive(Clone)]
+pub enum Target {
+    Pad(PadHandle),
+    Keyboard(KeyboardHandle),
+    Midi,
+}
+
+impl Target {
+    fn from_lua(v: mlua::Value) -> mlua::Result<Self> {
+        let tab = match v {
+            mlua::Value::Nil => return Ok(Target::Midi),
+            mlua::Value::Table(x) => x,
+            _ => return Err(mlua::Error::RuntimeError("expected a gamepad or keyboard".into())),
+        };
+        let handle = tab.get::<_, mlua::AnyUserData>("_handle")?;
+        if let Ok(pad) = handle.borrow::<PadHandle>() {
+            return Ok(Target::Pad(pad.clone()));
+        }
+        if let Ok(kbd) = handle.borrow::<KeyboardHandle>() {
+            return Ok(Target::Keyboard(kbd.clone()));
+        }
+
+        Err(mlua::Error::RuntimeError("expected a gamepad or keyboard".into()))
+    }
+
+    fn set_recorder(&self, l: &mlua::Lua, rec: Option<RecorderHandle>) {
+        match self {
+            Target::Pad(pad) => pad.0.lock().recorder = rec,
+            Target::Keyboard(kbd) => kbd.0.lock().recorder = rec,
+            Target::Midi => super::with_state(l, |x: &mut MacroState| x.midi_recorder = rec),
+        }
+    }
+
+    fn play(&self, l: &mlua::Lua, event: &MacroEvent) -> mlua::Result<()> {
+        match (self, event) {
+            (Target::Pad(pad), MacroEvent::Button { code, pressed }) => pad.0.lock().set_button(*code, *pressed),
+            (Target::Pad(pad), MacroEvent::Axis { code, value }) => pad.set_axis(l, *code, *value),
+            (Target::Keyboard(kbd), MacroEvent::Key { code, pressed }) => kbd.0.lock().set_key(*code, *pressed),
+            (_, MacroEvent::Midi { data }) => {
+                let time = MidiTime { played: true, ..MidiTime::now() };
+                let _ = script::sender(l).send(to_message(data, time));
+                Ok(())
+            },
+            // Events that the target can't do, like keys on a gamepad
+            _ => Ok(()),
+        }
+    }
+}
+
+struct Playback {
+    id: u64,
+    mac: Arc<Macro>,
+    target: Target,
+    start: Instant,
+    speed: f64,
+    looping: bool,
+    next: usize,
+}
+
+/// What the script is recording and playing.
+#[derive(Default)]
+struct MacroState {
+    /// Incoming MIDI being recorded.
+    midi_recorder: Option<RecorderHandle>,
+    next_id: u64,
+    playbacks: Vec<Playback>,
+}
+
+pub fn record_midi(l: &mlua::Lua, midi: &MidiMessage) {
+    if let Some(rec) = super::with_state(l, |x: &mut MacroState| x.midi_recorder.clone()) {
+        let data = util::midi_to_bytes(midi);
+        if !data.is_empty() {
+            rec.lock().push(MacroEvent::Midi { data });
+        }
+    }
+}
+
+/// Sends any macro events that are due.
+pub fn tick(l: &mlua::Lua) -> mlua::Result<()> {
+    let mut due = vec![];
+    super::with_state(l, |x: &mut MacroState| {
+        x.playbacks.retain_mut(|p| {
+            loop {
+                let elapsed = now().saturating_duration_since(p.start).as_secs_f64() * p.speed;
+                while let Some(step) = p.mac.steps.get(p.next) {
+                    if step.t > elapsed {
+                        break;
+                    }
+                    due.push((p.target.clone(), step.event.clone()));
+                    p.next += 1;
+                }
+
+                if p.next < p.mac.steps.len() || elapsed < p.mac.duration {
+                    return true;
+                }
+                if !p.looping || p.mac.duration <= 0.0 {
+                    return false;
+                }
+
+                // Start the next loop from where this one should have ended
+                let over = (elapsed - p.mac.duration) / p.speed;
+                p.start = now() - std::time::Duration::from_secs_f64(over);
+                p.next = 0;
+            }
+        });
+    });
+
+    for (target, event) in due {
+        target.play(l, &event)?;
+    }
+
+    Ok(())
+}
+
+fn create_macro_table<'lua>(l: &'lua mlua::Lua, mac: Arc<Macro>, source: Option<Target>) -> mlua::Result<mlua::Table<'lua>> {
+    let tab = l.create_table()?;
+
+    {
+        let mac = mac.clone();
+        tab.set("play", l.create_function(move |l, (opts,): (Option<mlua::Table>,)| {
+            let mut speed = 1.0;
+            let mut looping = false;
+            let mut target = source.clone();
+            if let Some(opts) = opts {
+                speed = opts.get::<_, Option<f64>>("speed")?.unwrap_or(speed);
+                looping = opts.get::<_, Option<bool>>("loop")?.unwrap_or(looping);
+                let t = opts.get::<_, mlua::Value>("target")?;
+                if t != mlua::Value::Nil {
+                    target = Some(Target::from_lua(t)?);
+                }
+            }
+            if speed <= 0.0 {
+                return Err(mlua::Error::RuntimeError("speed must be positive".into()));
+            }
+            let target = match target {
+                Some(x) => x,
+                None if mac.steps.iter().all(|x| matches!(x.event, MacroEvent::Midi { .. })) => Target::Midi,
+                None => return Err(mlua::Error::RuntimeError("this macro needs a target to play on".into())),
+            };
+
+            let id = super::with_state(l, |x: &mut MacroState| {
+                x.next_id += 1;
+                x.playbacks.push(Playback {
+                    id: x.next_id,
+                    mac: mac.clone(),
+                    target,
+                    start: now(),
+                    speed,
+                    looping,
+                    next: 0,
+                });
+                x.next_id
+            });
+
+            let tab = l.create_table()?;
+            tab.set("stop", l.create_function(move |l, _: ()| {
+                super::with_state(l, |x: &mut MacroState| x.playbacks.retain(|x| x.id != id));
+                Ok(())
+            })?)?;
+            tab.set("playing", l.create_function(move |l, _: ()| {
+                Ok(super::with_state(l, |x: &mut MacroState| x.playbacks.iter().any(|x| x.id == id)))
+            })?)?;
+
+            Ok(tab)
+        })?)?;
+    }
+
+    {
+        let mac = mac.clone();
+        tab.set("save", l.create_function(move |_l, (path,): (String,)| {
+            mac.save(Path::new(&path)).map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        })?)?;
+    }
+
+    {
+        let mac = mac.clone();
+        tab.set("duration", l.create_function(move |_l, _: ()| {
+            Ok(mac.duration)
+        })?)?;
+    }
+
+    Ok(tab)
+}
+
+pub struct Macros;
+impl ApiProvider for Macros {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        super::init_state(l, MacroState::default());
+        let tab = l.create_table()?;
+
+        // Records everything sent to a device, or incoming MIDI if no device is given
+        tab.set("record", l.create_function(|l, (target,): (mlua::Value,)| {
+            let target = Target::from_lua(target)?;
+            let rec = Arc::new(Mutex::new(Recorder {
+                start: now(),
+                steps: vec![],
+            }));
+            target.set_recorder(l, Some(rec.clone()));
+
+            let tab = l.create_table()?;
+            tab.set("stop", l.create_function(move |l, _: ()| {
+                target.set_recorder(l, None);
+                let rec = rec.lock();
+                let mac = Macro {
+                    duration: now().saturating_duration_since(rec.start).as_secs_f64(),
+                    steps: rec.steps.clone(),
+                };
+                create_macro_table(l, Arc::new(mac), Some(target.clone()))
+            })?)?;
+
+            Ok(tab)
+        })?)?;
+
+        tab.set("load", l.create_function(|l, (path,): (String,)| {
+            let mac = Macro::load(Path::new(&path)).map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+            create_macro_table(l, Arc::new(mac), None)
+        })?)?;
+
+        tab.set("stop_all", l.create_function(|l, _: ()| {
+            super::with_state(l, |x: &mut MacroState| x.playbacks.clear());
+            Ok(())
+        })?)?;
+
+        l.globals().set("macro", tab)?;
+
+        Ok(())
+    }
+}
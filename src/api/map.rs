@@ -0,0 +1,459 @@
+use std::{collections::HashMap, sync::Arc};
+use input_linux::{EventKind, InputEvent};
+use midi_control::MidiMessage;
+use mlua::{UserData, RegistryKey};
+use crate::util;
+
+use super::{ApiProvider, gamepad::PadHandle, keyboard::KeyboardHandle, midi::{MidiOutHandle, status_byte}};
+
+const BASE_LAYER: &str = "base";
+
+/// Something that can be bound in a layer. A channel or device of `None` matches any.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Source {
+    Note { channel: Option<i8>, key: u8 },
+    Control { channel: Option<i8>, control: u8 },
+    PitchBend { channel: Option<i8> },
+    /// A key or button on an input device.
+    Key { device: Option<u32>, code: u16 },
+    /// An absolute axis on an input device.
+    Abs { device: Option<u32>, code: u16 },
+}
+
+impl Source {
+    fn matches(&self, other: &Source) -> bool {
+        fn ch<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+            a.is_none() || a == b
+        }
+
+        match (self, other) {
+            (Source::Note { channel: a, key: x }, Source::Note { channel: b, key: y }) => ch(a, b) && x == y,
+            (Source::Control { channel: a, control: x }, Source::Control { channel: b, control: y }) => ch(a, b) && x == y,
+            (Source::PitchBend { channel: a }, Source::PitchBend { channel: b }) => ch(a, b),
+            (Source::Key { device: a, code: x }, Source::Key { device: b, code: y }) => ch(a, b) && x == y,
+            (Source::Abs { device: a, code: x }, Source::Abs { device: b, code: y }) => ch(a, b) && x == y,
+            _ => false,
+        }
+    }
+}
+
+/// A normalised input event, `value` is always in 0..1.
+pub struct Input {
+    pub source: Source,
+    pub pressed: bool,
+    pub value: f32,
+}
+
+impl Input {
+    pub fn from_midi(midi: &MidiMessage) -> Option<Self> {
+        match midi {
+            MidiMessage::NoteOn(ch, k) => Some(Self {
+                source: Source::Note { channel: Some(util::midi_channel_to_num(ch)), key: k.key },
+                pressed: k.value > 0,
+                value: k.value as f32 / 127.0,
+            }),
+            MidiMessage::NoteOff(ch, k) => Some(Self {
+                source: Source::Note { channel: Some(util::midi_channel_to_num(ch)), key: k.key },
+                pressed: false,
+                value: 0.0,
+            }),
+            MidiMessage::ControlChange(ch, cc) => Some(Self {
+                source: Source::Control { channel: Some(util::midi_channel_to_num(ch)), control: cc.control },
+                pressed: cc.value >= 64,
+                value: cc.value as f32 / 127.0,
+            }),
+            MidiMessage::PitchBend(ch, lsb, msb) => {
+                let raw = ((*msb as u16) << 7) | *lsb as u16;
+                Some(Self {
+                    source: Source::PitchBend { channel: Some(util::midi_channel_to_num(ch)) },
+                    pressed: (raw as i32 - 8192).abs() > 4096,
+                    value: raw as f32 / 16383.0,
+                })
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_evdev(device: u32, ev: &InputEvent) -> Option<Self> {
+        match ev.kind {
+            // 2 is autorepeat, which still counts as held
+            EventKind::Key => Some(Self {
+                source: Source::Key { device: Some(device), code: ev.code },
+                pressed: ev.value != 0,
+                value: if ev.value != 0 { 1.0 } else { 0.0 },
+            }),
+            EventKind::Absolute => {
+                let value = super::evdev::normalise(device, ev.code, ev.value);
+                Some(Self {
+                    source: Source::Abs { device: Some(device), code: ev.code },
+                    pressed: value > 0.5,
+                    value,
+                })
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Output {
+    Button(PadHandle, i32),
+    Axis { pad: PadHandle, axis: i32, min: f32, max: f32 },
+    Key(KeyboardHandle, i32),
+    Macro(Arc<RegistryKey>),
+    Momentary(String),
+    Toggle(String),
+    /// `velocity` of `None` follows the input.
+    Note { out: MidiOutHandle, channel: u8, key: u8, velocity: Option<u8> },
+    Cc { out: MidiOutHandle, channel: u8, control: u8, min: f32, max: f32 },
+    PitchBend { out: MidiOutHandle, channel: u8, min: f32, max: f32 },
+}
+
+struct Layer {
+    name: String,
+    bindings: Vec<(Source, Output)>,
+}
+
+struct Mapper {
+    layers: Vec<Layer>,
+    // Active layers besides the base layer, most recently activated last
+    active: Vec<String>,
+    // Inputs that are currently pressed keep going to whatever they were
+    // pressed on, even if the layers change underneath them
+    held: HashMap<Source, Output>,
+}
+
+impl Mapper {
+    fn new() -> Self {
+        Self {
+            layers: vec![Layer { name: BASE_LAYER.into(), bindings: vec![] }],
+            active: vec![],
+            held: HashMap::new(),
+        }
+    }
+
+    fn layer_mut(&mut self, name: &str) -> &mut Layer {
+        let idx = match self.layers.iter().position(|x| x.name == name) {
+            Some(x) => x,
+            None => {
+                self.layers.push(Layer { name: name.into(), bindings: vec![] });
+                self.layers.len() - 1
+            },
+        };
+        &mut self.layers[idx]
+    }
+
+    fn resolve(&self, source: &Source) -> Option<Output> {
+        let order = self.active.iter().rev().map(|x| x.as_str()).chain(std::iter::once(BASE_LAYER));
+        for name in order {
+            let layer = match self.layers.iter().find(|x| x.name == name) {
+                Some(x) => x,
+                None => continue,
+            };
+            if let Some((_, out)) = layer.bindings.iter().find(|(s, _)| s.matches(source)) {
+                return Some(out.clone());
+            }
+        }
+
+        None
+    }
+
+    fn is_active(&self, name: &str) -> bool {
+        name == BASE_LAYER || self.active.iter().any(|x| x == name)
+    }
+
+    fn active_layers(&self) -> Vec<String> {
+        let mut layers = vec![BASE_LAYER.to_string()];
+        layers.extend(self.active.iter().cloned());
+        layers
+    }
+
+    fn set_active(&mut self, name: &str, active: bool) {
+        if name == BASE_LAYER {
+            return;
+        }
+        self.active.retain(|x| x != name);
+        if active {
+            self.active.push(name.into());
+        }
+    }
+}
+
+/// The script's layers and bindings.
+fn with_mapper<R>(l: &mlua::Lua, f: impl FnOnce(&mut Mapper) -> R) -> R {
+    super::with_state(l, f)
+}
+
+pub fn set_active(l: &mlua::Lua, name: &str, active: bool) {
+    with_mapper(l, |x| x.set_active(name, active));
+}
+
+pub fn active_layers(l: &mlua::Lua) -> Vec<String> {
+    with_mapper(l, |x| x.active_layers())
+}
+
+pub fn bind(l: &mlua::Lua, layer: &str, source: Source, output: Output) {
+    with_mapper(l, |mapper| {
+        let layer = mapper.layer_mut(layer);
+        layer.bindings.retain(|(s, _)| *s != source);
+        layer.bindings.push((source, output));
+    });
+}
+
+pub fn handle(l: &mlua::Lua, input: Input) -> mlua::Result<()> {
+    // Work out what to do while locked, then do it unlocked, since
+    // macros can call back into the mapping API
+    let (output, rising, falling) = with_mapper(l, |mapper| {
+        let was_pressed = mapper.held.contains_key(&input.source);
+        let output = match (was_pressed, input.pressed) {
+            (true, true) => mapper.held.get(&input.source).cloned(),
+            (true, false) => mapper.held.remove(&input.source),
+            (false, true) => {
+                let out = mapper.resolve(&input.source);
+                if let Some(out) = &out {
+                    mapper.held.insert(input.source, out.clone());
+                }
+                out
+            },
+            (false, false) => mapper.resolve(&input.source),
+        };
+
+        (output, !was_pressed && input.pressed, was_pressed && !input.pressed)
+    });
+
+    let output = match output {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    match output {
+        Output::Button(pad, btn) => pad.0.lock().set_button(btn, input.pressed),
+        Output::Axis { pad, axis, min, max } => {
+            pad.set_axis(l, axis, min + (max - min) * input.value)
+        },
+        Output::Key(kbd, key) => kbd.0.lock().set_key(key, input.pressed),
+        Output::Macro(key) => {
+            let f = l.registry_value::<mlua::Function>(&key)?;
+            f.call::<_, ()>((input.value, input.pressed))
+        },
+        Output::Momentary(layer) => {
+            set_active(l, &layer, input.pressed);
+            Ok(())
+        },
+        Output::Toggle(layer) => {
+            if rising {
+                with_mapper(l, |mapper| {
+                    let active = mapper.is_active(&layer);
+                    mapper.set_active(&layer, !active);
+                });
+            }
+            Ok(())
+        },
+        Output::Note { out, channel, key, velocity } => {
+            if rising {
+                let vel = velocity.unwrap_or_else(|| ((input.value * 127.0).round() as u8).max(1));
+                out.0.lock().send(&[status_byte(0x90, channel)?, key, vel])
+            } else if falling {
+                out.0.lock().send(&[status_byte(0x80, channel)?, key, 0])
+            } else {
+                Ok(())
+            }
+        },
+        Output::Cc { out, channel, control, min, max } => {
+            let value = ((min + (max - min) * input.value).clamp(0.0, 1.0) * 127.0).round() as u8;
+            out.0.lock().send(&[status_byte(0xb0, channel)?, control, value])
+        },
+        Output::PitchBend { out, channel, min, max } => {
+            let bend = (min + (max - min) * input.value).clamp(-1.0, 1.0);
+            let value = ((bend * 8192.0) + 8192.0).round().clamp(0.0, 16383.0) as u16;
+            out.0.lock().send(&[status_byte(0xe0, channel)?, (value & 0x7f) as u8, (value >> 7) as u8])
+        },
+    }
+}
+
+pub fn handle_midi(l: &mlua::Lua, midi: &MidiMessage) -> mlua::Result<()> {
+    match Input::from_midi(midi) {
+        Some(x) => handle(l, x),
+        None => Ok(()),
+    }
+}
+
+pub fn handle_evdev(l: &mlua::Lua, device: u32, ev: &InputEvent) -> mlua::Result<()> {
+    match Input::from_evdev(device, ev) {
+        Some(x) => handle(l, x),
+        None => Ok(()),
+    }
+}
+
+pub struct LuaSource(pub Source);
+impl UserData for LuaSource {}
+
+pub struct LuaOutput(pub Output);
+impl UserData for LuaOutput {}
+
+fn pad_handle(tab: &mlua::Table) -> mlua::Result<PadHandle> {
+    let handle = tab.get::<_, mlua::AnyUserData>("_handle")?;
+    let handle = handle.borrow::<PadHandle>()?;
+    Ok(handle.clone())
+}
+
+fn keyboard_handle(tab: &mlua::Table) -> mlua::Result<KeyboardHandle> {
+    let handle = tab.get::<_, mlua::AnyUserData>("_handle")?;
+    let handle = handle.borrow::<KeyboardHandle>()?;
+    Ok(handle.clone())
+}
+
+fn midi_out_handle(tab: &mlua::Table) -> mlua::Result<MidiOutHandle> {
+    let handle = tab.get::<_, mlua::AnyUserData>("_handle")?;
+    let handle = handle.borrow::<MidiOutHandle>()?;
+    Ok(handle.clone())
+}
+
+fn device_id(dev: Option<mlua::Table>) -> mlua::Result<Option<u32>> {
+    match dev {
+        Some(dev) => Ok(Some(dev.get::<_, u32>("id")?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads min/max/invert from an options table.
+fn range(opts: Option<mlua::Table>, default: (f32, f32)) -> mlua::Result<(f32, f32)> {
+    let (mut min, mut max) = default;
+    if let Some(opts) = opts {
+        min = opts.get::<_, Option<f32>>("min")?.unwrap_or(min);
+        max = opts.get::<_, Option<f32>>("max")?.unwrap_or(max);
+        if opts.get::<_, Option<bool>>("invert")?.unwrap_or(false) {
+            std::mem::swap(&mut min, &mut max);
+        }
+    }
+    Ok((min, max))
+}
+
+pub struct Map;
+impl ApiProvider for Map {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        super::init_state(l, Mapper::new());
+        let tab = l.create_table()?;
+
+        tab.set("BASE", BASE_LAYER)?;
+
+        // Sources
+        tab.set("note", l.create_function(|_l, (key, channel): (u8, Option<i8>)| {
+            Ok(LuaSource(Source::Note { channel, key }))
+        })?)?;
+
+        tab.set("cc", l.create_function(|_l, (control, channel): (u8, Option<i8>)| {
+            Ok(LuaSource(Source::Control { channel, control }))
+        })?)?;
+
+        tab.set("pitch_bend", l.create_function(|_l, (channel,): (Option<i8>,)| {
+            Ok(LuaSource(Source::PitchBend { channel }))
+        })?)?;
+
+        // Devices from evdev.open, or nil for any device
+        tab.set("evdev_key", l.create_function(|_l, (code, dev): (u16, Option<mlua::Table>)| {
+            Ok(LuaSource(Source::Key { device: device_id(dev)?, code }))
+        })?)?;
+
+        tab.set("evdev_axis", l.create_function(|_l, (code, dev): (u16, Option<mlua::Table>)| {
+            Ok(LuaSource(Source::Abs { device: device_id(dev)?, code }))
+        })?)?;
+
+        // Outputs
+        tab.set("button", l.create_function(|_l, (pad, btn): (mlua::Table, i32)| {
+            Ok(LuaOutput(Output::Button(pad_handle(&pad)?, btn)))
+        })?)?;
+
+        tab.set("axis", l.create_function(|_l, (pad, axis, opts): (mlua::Table, i32, Option<mlua::Table>)| {
+            let (min, max) = range(opts, (-1.0, 1.0))?;
+            Ok(LuaOutput(Output::Axis { pad: pad_handle(&pad)?, axis, min, max }))
+        })?)?;
+
+        tab.set("key", l.create_function(|_l, (kbd, key): (mlua::Table, i32)| {
+            Ok(LuaOutput(Output::Key(keyboard_handle(&kbd)?, key)))
+        })?)?;
+
+        tab.set("macro", l.create_function(|l, (f,): (mlua::Function,)| {
+            Ok(LuaOutput(Output::Macro(Arc::new(l.create_registry_value(f)?))))
+        })?)?;
+
+        // MIDI outputs from midi.create_virtual_output or midi.open_output
+        tab.set("midi_note", l.create_function(|_l, (out, channel, key, opts): (mlua::Table, u8, u8, Option<mlua::Table>)| {
+            status_byte(0x90, channel)?;
+            let velocity = match opts {
+                Some(opts) => opts.get::<_, Option<u8>>("velocity")?.map(|x| x.min(127)),
+                None => None,
+            };
+            Ok(LuaOutput(Output::Note { out: midi_out_handle(&out)?, channel, key: key & 0x7f, velocity }))
+        })?)?;
+
+        // min and max are in 0..1
+        tab.set("midi_cc", l.create_function(|_l, (out, channel, control, opts): (mlua::Table, u8, u8, Option<mlua::Table>)| {
+            status_byte(0xb0, channel)?;
+            let (min, max) = range(opts, (0.0, 1.0))?;
+            Ok(LuaOutput(Output::Cc { out: midi_out_handle(&out)?, channel, control: control & 0x7f, min, max }))
+        })?)?;
+
+        // min and max are in -1..1
+        tab.set("midi_pitch_bend", l.create_function(|_l, (out, channel, opts): (mlua::Table, u8, Option<mlua::Table>)| {
+            status_byte(0xe0, channel)?;
+            let (min, max) = range(opts, (-1.0, 1.0))?;
+            Ok(LuaOutput(Output::PitchBend { out: midi_out_handle(&out)?, channel, min, max }))
+        })?)?;
+
+        tab.set("momentary", l.create_function(|_l, (layer,): (String,)| {
+            Ok(LuaOutput(Output::Momentary(layer)))
+        })?)?;
+
+        tab.set("toggle", l.create_function(|_l, (layer,): (String,)| {
+            Ok(LuaOutput(Output::Toggle(layer)))
+        })?)?;
+
+        // Layers
+        tab.set("layer", l.create_function(|l, (name,): (String,)| {
+            with_mapper(l, |x| { x.layer_mut(&name); });
+
+            let tab = l.create_table()?;
+            tab.set("name", name.clone())?;
+
+            {
+                let name = name.clone();
+                tab.set("bind", l.create_function(move |l, (source, output): (mlua::AnyUserData, mlua::AnyUserData)| {
+                    let source = source.borrow::<LuaSource>()?.0;
+                    let output = output.borrow::<LuaOutput>()?.0.clone();
+                    bind(l, &name, source, output);
+                    Ok(())
+                })?)?;
+            }
+
+            {
+                let name = name.clone();
+                tab.set("clear", l.create_function(move |l, _: ()| {
+                    with_mapper(l, |x| x.layer_mut(&name).bindings.clear());
+                    Ok(())
+                })?)?;
+            }
+
+            Ok(tab)
+        })?)?;
+
+        tab.set("set_active", l.create_function(|l, (name, active): (String, bool)| {
+            set_active(l, &name, active);
+            Ok(())
+        })?)?;
+
+        tab.set("is_active", l.create_function(|l, (name,): (String,)| {
+            Ok(with_mapper(l, |x| x.is_active(&name)))
+        })?)?;
+
+        tab.set("active_layers", l.create_function(|l, _: ()| {
+            Ok(active_layers(l))
+        })?)?;
+
+        l.globals().set("map", tab)?;
+
+        Ok(())
+    }
+}
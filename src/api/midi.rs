@@ -1,14 +1,14 @@
-use std::{sync::{Arc, mpsc::Sender}};
-use midi_control::MidiMessage;
-use midir::{Ignore, MidiInputConnection};
+use std::{collections::{BTreeSet, HashMap}, sync::{Arc, Weak, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort, MidiOutput, MidiOutputConnection, os::unix::{VirtualInput, VirtualOutput}};
 use mlua::{Error::ExternalError};
 use parking_lot::Mutex;
-use crate::Message;
+use crate::{Message, MessageSender, hotplug::Device, script, util};
+use super::clock::ClockEvent;
 
 use super::ApiProvider;
 
 #[derive(Debug)]
-struct MidiError(pub String);
+pub struct MidiError(pub String);
 impl std::error::Error for MidiError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         None
@@ -24,8 +24,348 @@ impl std::fmt::Display for MidiError {
     }
 }
 
+/// Set when MIDI is coming from a replayed session or the test harness, so
+/// scripts don't go looking for hardware.
+pub static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn find_port(midi_in: &MidiInput, portno: usize) -> Result<MidiInputPort, MidiError> {
+    let in_ports = midi_in.ports();
+    match in_ports.len() {
+        0 => {
+            Err(MidiError("No ports on system.".into()))
+        },
+        1 => {
+            Ok(in_ports[0].clone())
+        },
+        _ => {
+            in_ports.get(portno).cloned().ok_or_else(|| MidiError(format!("No port {}.", portno)))
+        }
+    }
+}
+
+/// ALSA port names end in client and port numbers, like "MPK mini 3:MPK mini 3
+/// MIDI 1 20:0", which can change when a device is plugged back in. This is
+/// the name without them.
+pub fn port_key(name: &str) -> &str {
+    let numbered = |x: &str| !x.is_empty() && x.chars().all(|c| c.is_ascii_digit());
+    match name.rsplit_once(' ') {
+        Some((rest, nums)) if nums.split_once(':').is_some_and(|(a, b)| numbered(a) && numbered(b)) => rest,
+        _ => name,
+    }
+}
+
+/// Builds a status byte from a message kind (0x90 etc.) and a channel from 1 to 16.
+pub fn status_byte(kind: u8, channel: u8) -> mlua::Result<u8> {
+    if !(1..=16).contains(&channel) {
+        return Err(mlua::Error::RuntimeError(format!("MIDI channel {} is out of range (1-16)", channel)));
+    }
+    Ok(kind | (channel - 1))
+}
+
+/// When a MIDI message arrived.
+#[derive(Clone, Copy, Debug)]
+pub struct MidiTime {
+    /// From midir, in microseconds since a point that depends on the backend
+    /// (when the port was opened, on ALSA). 0 for MIDI that didn't come
+    /// from a port.
+    pub device: u64,
+    /// When the port got it, going by `device` where there is one, so it
+    /// doesn't include waiting for the MIDI callback.
+    pub received: Instant,
+    /// Sent by a playing macro, so it isn't recorded all over again.
+    pub played: bool,
+}
+
+impl MidiTime {
+    /// For MIDI that's made up rather than received.
+    pub fn now() -> Self {
+        Self { device: 0, received: super::misc::now(), played: false }
+    }
+}
+
+/// Turns raw MIDI into a message for the dispatcher.
+pub fn to_message(data: &[u8], time: MidiTime) -> Message {
+    match ClockEvent::parse(data) {
+        Some(x) => Message::Clock(x, time),
+        None => Message::Midi(util::bytes_to_midi(data), time),
+    }
+}
+
+/// Timestamps further behind than this mean the port's clock has started
+/// over, rather than that the callback is that late.
+const MAX_CALLBACK_DELAY: Duration = Duration::from_secs(1);
+
+/// What an input port's callback needs. Its timestamps count from a point
+/// that isn't an `Instant`, which is worked out from the message that got
+/// through soonest: none of them can have arrived after the callback saw it.
+struct Forward {
+    sender: MessageSender,
+    start: Option<Instant>,
+}
+
+impl Forward {
+    fn new(sender: MessageSender) -> Self {
+        Self { sender, start: None }
+    }
+
+    fn received(&mut self, ts: u64) -> Instant {
+        let now = Instant::now();
+        let since = Duration::from_micros(ts);
+        let start = match (self.start, now.checked_sub(since)) {
+            (_, None) => return now,
+            (Some(x), Some(y)) if x + since + MAX_CALLBACK_DELAY >= now => x.min(y),
+            (_, Some(y)) => y,
+        };
+        self.start = Some(start);
+        start + since
+    }
+}
+
+fn forward(ts: u64, data: &[u8], fwd: &mut Forward) {
+    // Without timestamps there's nothing better than now
+    let received = match ts {
+        0 => Instant::now(),
+        _ => fwd.received(ts),
+    };
+    let time = MidiTime { device: ts, received, played: false };
+    let _ = fwd.sender.send(to_message(data, time));
+}
+
+/// Somewhere MIDI can be sent.
+pub enum MidiSink {
+    Port(MidiOutputConnection),
+    /// Stands in for a port when MIDI is simulated.
+    Offline,
+    /// The device went away. It's connected again when it comes back.
+    Unplugged,
+}
+
+pub struct MidiOut {
+    pub name: String,
+    pub sink: MidiSink,
+    /// `port_key` of the hardware port, for reconnecting.
+    pub port: Option<String>,
+}
+
+impl MidiOut {
+    pub fn send(&mut self, data: &[u8]) -> mlua::Result<()> {
+        match &mut self.sink {
+            MidiSink::Port(conn) => conn.send(data).map_err(|e| mlua::Error::RuntimeError(format!("could not send MIDI to {}: {}", self.name, e))),
+            MidiSink::Offline => {
+                debug!("[offline] {}: {:02x?}", self.name, data);
+                Ok(())
+            },
+            MidiSink::Unplugged => {
+                debug!("[unplugged] {}: {:02x?}", self.name, data);
+                Ok(())
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MidiOutHandle(pub Arc<Mutex<MidiOut>>);
+impl mlua::UserData for MidiOutHandle {}
+
+fn create_output_table<'lua>(l: &'lua mlua::Lua, out: MidiOut) -> mlua::Result<mlua::Table<'lua>> {
+    let hardware = out.port.is_some();
+    let handle = MidiOutHandle(Arc::new(Mutex::new(out)));
+    if hardware {
+        HARDWARE_OUTPUTS.lock().push(Arc::downgrade(&handle.0));
+    }
+    let tab = l.create_table()?;
+    tab.set("_handle", handle.clone())?;
+
+    {
+        let handle = handle.clone();
+        tab.set("send", l.create_function(move |_l, (data,): (Vec<u8>,)| {
+            handle.0.lock().send(&data)
+        })?)?;
+    }
+
+    {
+        let handle = handle.clone();
+        tab.set("note_on", l.create_function(move |_l, (channel, key, vel): (u8, u8, Option<u8>)| {
+            handle.0.lock().send(&[status_byte(0x90, channel)?, key & 0x7f, vel.unwrap_or(127) & 0x7f])
+        })?)?;
+    }
+
+    {
+        let handle = handle.clone();
+        tab.set("note_off", l.create_function(move |_l, (channel, key, vel): (u8, u8, Option<u8>)| {
+            handle.0.lock().send(&[status_byte(0x80, channel)?, key & 0x7f, vel.unwrap_or(0) & 0x7f])
+        })?)?;
+    }
+
+    {
+        let handle = handle.clone();
+        tab.set("cc", l.create_function(move |_l, (channel, control, value): (u8, u8, u8)| {
+            handle.0.lock().send(&[status_byte(0xb0, channel)?, control & 0x7f, value & 0x7f])
+        })?)?;
+    }
+
+    // 14-bit value, 8192 is the centre
+    tab.set("pitch_bend", l.create_function(move |_l, (channel, value): (u8, u16)| {
+        let value = value.min(0x3fff);
+        handle.0.lock().send(&[status_byte(0xe0, channel)?, (value & 0x7f) as u8, (value >> 7) as u8])
+    })?)?;
+
+    Ok(tab)
+}
+
+/// An input port, or `None` while it's unplugged. It's closed once no
+/// script has it open.
+type Input = Arc<InputConn>;
+type InputConn = Mutex<Option<MidiInputConnection<Forward>>>;
+
 lazy_static::lazy_static! {
-    static ref MIDI_CONN: Arc<Mutex<Option<MidiInputConnection<Sender<Message>>>>> = Arc::new(Mutex::new(None));
+    /// Ports opened with midi.open, by `port_key`. Every script gets what
+    /// comes in on any of them, and opening one that's already open just
+    /// shares it.
+    static ref MIDI_CONNS: Mutex<HashMap<String, Weak<InputConn>>> = Mutex::new(HashMap::new());
+    /// Ports opened with midi.open_output, to reconnect when they're plugged back in.
+    static ref HARDWARE_OUTPUTS: Mutex<Vec<Weak<Mutex<MidiOut>>>> = Mutex::new(vec![]);
+    /// By name, shared the same way, so reloading a script doesn't make duplicates.
+    static ref VIRTUAL_INPUTS: Mutex<HashMap<String, Weak<InputConn>>> = Mutex::new(HashMap::new());
+}
+
+/// The input ports the script has open.
+#[derive(Default)]
+struct Inputs(Vec<Input>);
+
+/// Keeps a port open for as long as the script is running.
+fn hold(l: &mlua::Lua, input: Input) {
+    super::with_state(l, |x: &mut Inputs| {
+        if !x.0.iter().any(|x| Arc::ptr_eq(x, &input)) {
+            x.0.push(input);
+        }
+    });
+}
+
+/// A port that's still open.
+fn find_input(inputs: &Mutex<HashMap<String, Weak<InputConn>>>, key: &str) -> Option<Input> {
+    let mut inputs = inputs.lock();
+    inputs.retain(|_, x| x.strong_count() > 0);
+    inputs.get(key).and_then(|x| x.upgrade())
+}
+
+fn connect_input(midi_in: MidiInput, port: &MidiInputPort, name: &str, sender: MessageSender) -> Result<MidiInputConnection<Forward>, MidiError> {
+    midi_in.connect(port, name, forward, Forward::new(sender))
+        .map_err(|e| MidiError(format!("could not open {}: {}", name, e)))
+}
+
+/// How often to look for MIDI devices coming and going.
+const PORT_POLL: Duration = Duration::from_secs(1);
+
+/// Every port on the system, by `port_key`, besides our own virtual ones.
+fn port_keys(midi_in: &MidiInput, midi_out: &MidiOutput) -> BTreeSet<String> {
+    let ins = midi_in.ports().into_iter().filter_map(|x| midi_in.port_name(&x).ok());
+    let outs = midi_out.ports().into_iter().filter_map(|x| midi_out.port_name(&x).ok());
+    ins.chain(outs)
+        .map(|x| port_key(&x).to_string())
+        .filter(|x| !x.starts_with("handcake MIDI"))
+        .collect()
+}
+
+fn unplugged(key: &str) {
+    if let Some(conn) = find_input(&MIDI_CONNS, key) {
+        if conn.lock().take().is_some() {
+            warn!("MIDI input {} was unplugged", key);
+        }
+    }
+
+    let mut outputs = HARDWARE_OUTPUTS.lock();
+    outputs.retain(|x| x.strong_count() > 0);
+    for out in outputs.iter().filter_map(|x| x.upgrade()) {
+        let mut out = out.lock();
+        if out.port.as_deref() == Some(key) {
+            warn!("MIDI output {} was unplugged", out.name);
+            out.sink = MidiSink::Unplugged;
+        }
+    }
+}
+
+/// Connects anything that was using the port before it was unplugged.
+fn replugged(key: &str, sender: &MessageSender) -> Result<(), midir::InitError> {
+    let conn = find_input(&MIDI_CONNS, key);
+    if let Some(mut conn) = conn.as_ref().map(|x| x.lock()).filter(|x| x.is_none()) {
+        let mut midi_in = MidiInput::new("handcake MIDI input")?;
+        midi_in.ignore(Ignore::None);
+        let port = midi_in.ports().into_iter()
+            .find(|x| midi_in.port_name(x).is_ok_and(|x| port_key(&x) == key));
+        if let Some(port) = port {
+            let name = midi_in.port_name(&port).unwrap_or_default();
+            match connect_input(midi_in, &port, &name, sender.clone()) {
+                Ok(x) => {
+                    info!("MIDI input {} is back", key);
+                    *conn = Some(x);
+                },
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+
+    let outputs = HARDWARE_OUTPUTS.lock().iter().filter_map(|x| x.upgrade()).collect::<Vec<_>>();
+    for out in outputs {
+        let mut out = out.lock();
+        if out.port.as_deref() != Some(key) || !matches!(out.sink, MidiSink::Unplugged) {
+            continue;
+        }
+        let midi_out = MidiOutput::new("handcake MIDI output")?;
+        let port = midi_out.ports().into_iter()
+            .find(|x| midi_out.port_name(x).is_ok_and(|x| port_key(&x) == key));
+        if let Some(port) = port {
+            match midi_out.connect(&port, "handcake") {
+                Ok(conn) => {
+                    info!("MIDI output {} is back", out.name);
+                    out.sink = MidiSink::Port(conn);
+                },
+                Err(e) => warn!("Could not open MIDI output {} again: {}", out.name, e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Watches for MIDI devices being plugged in and unplugged, reconnecting
+/// anything that was open and telling the scripts. Runs until the
+/// dispatcher goes away.
+pub fn watch_ports(sender: MessageSender) {
+    std::thread::spawn(move || {
+        let (midi_in, midi_out) = match (MidiInput::new("handcake hotplug"), MidiOutput::new("handcake hotplug")) {
+            (Ok(a), Ok(b)) => (a, b),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Not watching for MIDI devices: {}", e);
+                return;
+            },
+        };
+
+        let mut known = port_keys(&midi_in, &midi_out);
+        loop {
+            std::thread::sleep(PORT_POLL);
+            let now = port_keys(&midi_in, &midi_out);
+
+            for key in known.difference(&now) {
+                unplugged(key);
+                let device = Device { kind: "midi", name: key.clone(), path: None, id: None };
+                if sender.send(Message::Device { added: false, device }).is_err() {
+                    return;
+                }
+            }
+            for key in now.difference(&known) {
+                if let Err(e) = replugged(key, &sender) {
+                    warn!("Could not reconnect {}: {}", key, e);
+                }
+                let device = Device { kind: "midi", name: key.clone(), path: None, id: None };
+                if sender.send(Message::Device { added: true, device }).is_err() {
+                    return;
+                }
+            }
+
+            known = now;
+        }
+    });
 }
 
 pub struct Midi;
@@ -33,48 +373,125 @@ impl ApiProvider for Midi {
     type Arguments = ();
 
     fn register_api(l: &mlua::Lua, _: Self::Arguments) -> anyhow::Result<()> {
+        super::init_state(l, Inputs::default());
         let tab = l.create_table()?;
 
 
-        tab.set("open", l.create_function(|_l, (portno,): (usize,)| {
+        tab.set("open", l.create_function(|l, (portno,): (usize,)| {
+            if OFFLINE.load(Ordering::Relaxed) {
+                info!("MIDI is simulated, not opening port {}", portno);
+                return Ok(());
+            }
+
             let mut midi_in = midir::MidiInput::new("handcake MIDI input").unwrap();
             midi_in.ignore(Ignore::None);
-            let in_ports = midi_in.ports();
-            let port = match in_ports.len() {
-                0 => {
-                    return Err(ExternalError(Arc::new(MidiError("No ports on system.".into()))));
-                },
-                1 => {
-                    &in_ports[0]
-                },
-                _ => {
-                    in_ports.get(portno).unwrap()
-                }
-            };
+            let port = &find_port(&midi_in, portno).map_err(|e| ExternalError(Arc::new(e)))?;
 
             let name = midi_in.port_name(port).unwrap();
+            let key = port_key(&name).to_string();
+            if let Some(conn) = find_input(&MIDI_CONNS, &key) {
+                hold(l, conn);
+                return Ok(());
+            }
 
-            {
-                let (_snd, _) = crate::MESSAGE.clone();
-                let _snd = _snd.lock();
-                let sender = _snd.clone();
-                drop(_snd);
+            let conn = connect_input(midi_in, port, &name, script::sender(l)).map_err(|e| ExternalError(Arc::new(e)))?;
+            let conn = Arc::new(Mutex::new(Some(conn)));
+            MIDI_CONNS.lock().insert(key, Arc::downgrade(&conn));
+            hold(l, conn);
 
-                let conn = midi_in.connect(port, &name, |_ts, data, sender|
-                {
-                    sender.send(Message::Midi(MidiMessage::from(data))).unwrap();
-                },
-                sender).unwrap();
+            Ok(())
+        })?)?;
 
-                *MIDI_CONN.as_ref().lock() = Some(conn);
+        // A hardware (or other program's) port to send MIDI to, numbered like midi.open
+        tab.set("open_output", l.create_function(|l, (portno,): (usize,)| {
+            let name = format!("output {}", portno);
+            if OFFLINE.load(Ordering::Relaxed) {
+                info!("MIDI is simulated, not opening output port {}", portno);
+                return create_output_table(l, MidiOut { name, sink: MidiSink::Offline, port: None });
             }
 
+            let midi_out = midir::MidiOutput::new("handcake MIDI output").map_err(|e| ExternalError(Arc::new(e)))?;
+            let ports = midi_out.ports();
+            let port = match ports.len() {
+                0 => Err(MidiError("No output ports on system.".into())),
+                1 => Ok(ports[0].clone()),
+                _ => ports.get(portno).cloned().ok_or_else(|| MidiError(format!("No output port {}.", portno))),
+            }.map_err(|e| ExternalError(Arc::new(e)))?;
+            let name = midi_out.port_name(&port).unwrap_or(name);
+
+            let conn = midi_out.connect(&port, "handcake")
+                .map_err(|e| ExternalError(Arc::new(MidiError(format!("could not open output {}: {}", name, e)))))?;
+            info!("Opened MIDI output {}", name);
 
+            let port = Some(port_key(&name).to_string());
+            create_output_table(l, MidiOut { name, sink: MidiSink::Port(conn), port })
+        })?)?;
+
+        // A port other programs can send MIDI into, which arrives like any other MIDI
+        tab.set("create_virtual_input", l.create_function(|l, (name,): (String,)| {
+            if OFFLINE.load(Ordering::Relaxed) {
+                info!("MIDI is simulated, not creating virtual input {}", name);
+                return Ok(());
+            }
+            if let Some(conn) = find_input(&VIRTUAL_INPUTS, &name) {
+                hold(l, conn);
+                return Ok(());
+            }
+
+            let mut midi_in = midir::MidiInput::new("handcake MIDI input").map_err(|e| ExternalError(Arc::new(e)))?;
+            midi_in.ignore(Ignore::None);
+            let conn = midi_in.create_virtual(&name, forward, Forward::new(script::sender(l)))
+                .map_err(|e| ExternalError(Arc::new(MidiError(format!("could not create virtual input {}: {}", name, e)))))?;
+            info!("Created virtual MIDI input {}", name);
+
+            let conn = Arc::new(Mutex::new(Some(conn)));
+            VIRTUAL_INPUTS.lock().insert(name, Arc::downgrade(&conn));
+            hold(l, conn);
             Ok(())
         })?)?;
 
+        // A port other programs can read from, returned as an object with send/note_on/... on it
+        tab.set("create_virtual_output", l.create_function(|l, (name,): (String,)| {
+            let sink = if OFFLINE.load(Ordering::Relaxed) {
+                info!("MIDI is simulated, not creating virtual output {}", name);
+                MidiSink::Offline
+            } else {
+                let midi_out = midir::MidiOutput::new("handcake MIDI output").map_err(|e| ExternalError(Arc::new(e)))?;
+                let conn = midi_out.create_virtual(&name)
+                    .map_err(|e| ExternalError(Arc::new(MidiError(format!("could not create virtual output {}: {}", name, e)))))?;
+                info!("Created virtual MIDI output {}", name);
+                MidiSink::Port(conn)
+            };
+
+            create_output_table(l, MidiOut { name, sink, port: None })
+        })?)?;
+
         l.globals().set("midi", tab)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_become_instants() {
+        let (sender, _) = tokio::sync::mpsc::unbounded_channel();
+        let mut fwd = Forward::new(sender);
+        let first = fwd.received(5_000_000);
+        // Later messages go by the first one's timestamp, not by when they're handled
+        std::thread::sleep(Duration::from_millis(20));
+        let second = fwd.received(5_001_000);
+        assert_eq!(second - first, Duration::from_millis(1));
+
+        // One that got through quicker than the first moves the start back
+        let third = fwd.received(5_100_000);
+        assert!(third <= Instant::now());
+        assert!(first - fwd.received(5_000_000) > Duration::from_millis(10));
+
+        // Going backwards is the port's clock starting over
+        let restarted = fwd.received(1_000);
+        assert!(Instant::now() - restarted < MAX_CALLBACK_DELAY);
+    }
+}
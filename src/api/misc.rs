@@ -1,17 +1,50 @@
-use std::{time::Duration, sync::Arc};
+use std::{time::{Duration, Instant}, sync::Arc};
 
 use parking_lot::Mutex;
 
 use super::ApiProvider;
 
 lazy_static::lazy_static! {
-    static ref START_TIME: std::time::Instant = {
+    /// What `misc.now_ns()` and receive times count from.
+    pub static ref START_TIME: std::time::Instant = {
         std::time::Instant::now()
     };
 
     static ref DELTA: Arc<Mutex<std::time::Instant>> = {
-        Arc::new(Mutex::new(std::time::Instant::now()))
+        Arc::new(Mutex::new(now()))
     };
+
+    /// Set while time is simulated, for tests and replays. It then stands
+    /// still apart from `advance`.
+    static ref SIMULATED: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// The time that anything time-based (filters, modifiers, macros, the MIDI
+/// clock) goes by, which is simulated in tests and replays.
+pub fn now() -> Instant {
+    SIMULATED.lock().unwrap_or_else(Instant::now)
+}
+
+/// Stops the clock, so from now on only `advance` moves it.
+pub fn simulate() {
+    SIMULATED.lock().get_or_insert_with(Instant::now);
+}
+
+pub fn simulated() -> bool {
+    SIMULATED.lock().is_some()
+}
+
+/// Moves simulated time on. Does nothing to the real thing.
+pub fn advance(by: Duration) {
+    if let Some(t) = SIMULATED.lock().as_mut() {
+        *t += by;
+    }
+}
+
+/// Nanoseconds from handcake starting to `t`, the clock `misc.now_ns()` and
+/// event receive times are on.
+pub fn nanos_since_start(t: std::time::Instant) -> i64 {
+    t.saturating_duration_since(*START_TIME).as_nanos() as i64
 }
 
 pub struct Misc;
@@ -22,24 +55,31 @@ impl ApiProvider for Misc {
         let tab = l.create_table()?;
 
         tab.set("sleep", l.create_function(|_l, (time,): (f32,)| {
+            if time < 0.0 || !time.is_finite() {
+                return Err(mlua::Error::RuntimeError(format!("can't sleep for {} seconds", time)));
+            }
             std::thread::sleep(Duration::from_secs_f32(time));
             Ok(())
         })?)?;
 
         tab.set("time", l.create_function(|_l, _: ()| {
-            let t = std::time::Instant::now();
-            let elapsed = t.duration_since(*START_TIME);
+            let elapsed = now().saturating_duration_since(*START_TIME);
             let millis: u32 = elapsed.as_millis() as u32;
             let millis: f64 = millis.into();
 
             Ok(millis / 1000f64)
         })?)?;
 
+        // Monotonic, in nanoseconds since handcake started
+        tab.set("now_ns", l.create_function(|_l, _: ()| {
+            Ok(nanos_since_start(now()))
+        })?)?;
+
         tab.set("delta_time", l.create_function(|_l, _: ()| {
-            let t = DELTA.lock().elapsed();
+            let t = now().saturating_duration_since(*DELTA.lock());
             let millis = t.as_millis() as u32;
             let millis: f64 = millis.into();
-            *DELTA.lock() = std::time::Instant::now();
+            *DELTA.lock() = now();
 
             Ok(millis / 1000f64)
         })?)?;
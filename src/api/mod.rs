@@ -1,6 +1,35 @@
+use parking_lot::Mutex;
+
 pub mod midi;
 pub mod gamepad;
 pub mod misc;
+pub mod filter;
+pub mod keyboard;
+pub mod map;
+pub mod macros;
+pub mod sim;
+pub mod evdev;
+pub mod osc;
+pub mod bus;
+pub mod package;
+pub mod focus;
+pub mod clock;
+#[cfg(feature = "web")]
+pub mod web;
+
+/// Keeps an API's state for one script in its Lua state, so it goes away
+/// along with the script. Called when the API is registered.
+pub fn init_state<T: Send + 'static>(l: &mlua::Lua, state: T) {
+    l.set_app_data(Mutex::new(state));
+}
+
+/// Runs `f` on state kept with `init_state`. The Lua state's app data can't
+/// be borrowed mutably meanwhile, so `f` mustn't call back into Lua.
+pub fn with_state<T: 'static, R>(l: &mlua::Lua, f: impl FnOnce(&mut T) -> R) -> R {
+    let state = l.app_data_ref::<Mutex<T>>().expect("API state was not set up");
+    let mut state = state.lock();
+    f(&mut state)
+}
 
 pub trait ApiProvider {
     type Arguments;
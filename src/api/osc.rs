@@ -0,0 +1,139 @@
+use std::{collections::HashSet, net::{ToSocketAddrs, UdpSocket}, sync::atomic::Ordering, time::Instant};
+use parking_lot::Mutex;
+
+use crate::{Message, MessageSender, osc::{self, OscArg, OscMessage}, sandbox, script};
+use super::{ApiProvider, midi::OFFLINE};
+
+lazy_static::lazy_static! {
+    /// Shared by everything sent, bound to whatever port is free.
+    static ref SEND_SOCKET: Mutex<Option<UdpSocket>> = Mutex::new(None);
+    /// Addresses already being listened on. These outlive the script, so
+    /// reloading it doesn't try to bind them again.
+    static ref LISTENING: Mutex<HashSet<(String, u16)>> = Mutex::new(HashSet::new());
+}
+
+pub fn arg_to_lua<'lua>(l: &'lua mlua::Lua, arg: &OscArg) -> mlua::Result<mlua::Value<'lua>> {
+    Ok(match arg {
+        OscArg::Int(x) => mlua::Value::Integer(*x as i64),
+        OscArg::Long(x) => mlua::Value::Integer(*x),
+        OscArg::Float(x) => mlua::Value::Number(*x as f64),
+        OscArg::Double(x) => mlua::Value::Number(*x),
+        OscArg::Str(x) => mlua::Value::String(l.create_string(x)?),
+        OscArg::Blob(x) => mlua::Value::String(l.create_string(x)?),
+        OscArg::Bool(x) => mlua::Value::Boolean(*x),
+        OscArg::Nil => mlua::Value::Nil,
+    })
+}
+
+/// Integers are sent as `i` and other numbers as `f`, which is what most
+/// control surfaces expect.
+pub fn lua_to_arg(v: mlua::Value) -> mlua::Result<OscArg> {
+    Ok(match v {
+        mlua::Value::Integer(x) => match i32::try_from(x) {
+            Ok(x) => OscArg::Int(x),
+            Err(_) => OscArg::Long(x),
+        },
+        mlua::Value::Number(x) => OscArg::Float(x as f32),
+        mlua::Value::String(x) => OscArg::Str(x.to_str()?.to_string()),
+        mlua::Value::Boolean(x) => OscArg::Bool(x),
+        mlua::Value::Nil => OscArg::Nil,
+        x => return Err(mlua::Error::RuntimeError(format!("can't send a {} over OSC", x.type_name()))),
+    })
+}
+
+fn listen(socket: UdpSocket, sender: MessageSender) {
+    let mut buf = [0u8; 65536];
+
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Stopped listening for OSC: {}", e);
+                break;
+            },
+        };
+
+        let received = Instant::now();
+        match osc::decode(&buf[..n]) {
+            Ok(msgs) => {
+                for msg in msgs {
+                    if sender.send(Message::Osc(msg, received)).is_err() {
+                        return;
+                    }
+                }
+            },
+            Err(e) => warn!("Bad OSC packet from {}: {}", from, e),
+        }
+    }
+}
+
+pub struct Osc;
+impl ApiProvider for Osc {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        let tab = l.create_table()?;
+
+        // Incoming messages go to on_osc_recv(addr, args, received_ns).
+        // Only from this machine in the sandbox
+        tab.set("listen", l.create_function(|l, (port, host): (u16, Option<String>)| {
+            let sandboxed = sandbox::sandboxed(l);
+            let host = host.unwrap_or_else(|| match sandboxed {
+                true => "127.0.0.1".into(),
+                false => "0.0.0.0".into(),
+            });
+            if sandboxed {
+                let on = (host.as_str(), port).to_socket_addrs()
+                    .map_err(|e| mlua::Error::RuntimeError(format!("could not listen on {}:{}: {}", host, port, e)))?
+                    .collect::<Vec<_>>();
+                if !on.iter().all(|x| x.ip().is_loopback()) {
+                    return Err(mlua::Error::RuntimeError(format!("can't listen for OSC on {} from the sandbox", host)));
+                }
+            }
+            if OFFLINE.load(Ordering::Relaxed) {
+                info!("OSC is simulated, not listening on {}:{}", host, port);
+                return Ok(());
+            }
+            let mut listening = LISTENING.lock();
+            if listening.contains(&(host.clone(), port)) {
+                return Ok(());
+            }
+
+            let socket = UdpSocket::bind((host.as_str(), port))
+                .map_err(|e| mlua::Error::RuntimeError(format!("could not listen on {}:{}: {}", host, port, e)))?;
+            info!("Listening for OSC on {}:{}", host, port);
+            let sender = script::sender(l);
+            std::thread::spawn(move || listen(socket, sender));
+            listening.insert((host, port));
+
+            Ok(())
+        })?)?;
+
+        // Only to this machine in the sandbox
+        tab.set("send", l.create_function(|l, (host, port, addr, args): (String, u16, String, mlua::Variadic<mlua::Value>)| {
+            let to = (host.as_str(), port).to_socket_addrs()
+                .map_err(|e| mlua::Error::RuntimeError(format!("could not send OSC to {}:{}: {}", host, port, e)))?
+                .collect::<Vec<_>>();
+            if sandbox::sandboxed(l) && !to.iter().all(|x| x.ip().is_loopback()) {
+                return Err(mlua::Error::RuntimeError(format!("can't send OSC to {} from the sandbox", host)));
+            }
+            let msg = OscMessage {
+                addr,
+                args: args.into_iter().map(lua_to_arg).collect::<mlua::Result<_>>()?,
+            };
+
+            let mut socket = SEND_SOCKET.lock();
+            if socket.is_none() {
+                *socket = Some(UdpSocket::bind("0.0.0.0:0").map_err(|e| mlua::Error::RuntimeError(e.to_string()))?);
+            }
+            socket.as_ref().unwrap().send_to(&osc::encode(&msg), &to[..])
+                .map_err(|e| mlua::Error::RuntimeError(format!("could not send OSC to {}:{}: {}", host, port, e)))?;
+
+            Ok(())
+        })?)?;
+
+        l.globals().set("osc", tab)?;
+
+        Ok(())
+    }
+}
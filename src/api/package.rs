@@ -0,0 +1,47 @@
+use std::path::{Path, PathBuf};
+
+use super::ApiProvider;
+
+/// Modules built into handcake, which `require` finds before anything on disk.
+const STDLIB: &[(&str, &str)] = &[
+    ("handcake.util", include_str!("../lua/handcake/util.lua")),
+];
+
+/// Where modules shared between scripts go, `$XDG_DATA_HOME/handcake/lib`.
+pub fn lib_dir() -> Option<PathBuf> {
+    let data = match std::env::var_os("XDG_DATA_HOME") {
+        Some(x) => PathBuf::from(x),
+        None => Path::new(&std::env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(data.join("handcake/lib"))
+}
+
+/// Lets `require` find modules next to the script and in the library
+/// directory, as well as the built-in ones.
+pub struct Package;
+impl ApiProvider for Package {
+    /// The directory the script is in.
+    type Arguments = (Option<PathBuf>,);
+
+    fn register_api(l: &mlua::Lua, args: Self::Arguments) -> anyhow::Result<()> {
+        let (dir,) = args;
+        let package = l.globals().get::<_, mlua::Table>("package")?;
+
+        let mut paths = vec![];
+        for dir in dir.into_iter().chain(lib_dir()) {
+            let dir = dir.to_string_lossy();
+            paths.push(format!("{}/?.lua", dir));
+            paths.push(format!("{}/?/init.lua", dir));
+        }
+        paths.push(package.get::<_, String>("path")?);
+        package.set("path", paths.join(";"))?;
+
+        let preload = package.get::<_, mlua::Table>("preload")?;
+        for (name, source) in STDLIB {
+            let chunk = l.load(source).set_name(&format!("={}", name))?.into_function()?;
+            preload.set(*name, chunk)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use input_linux::{EventKind, EventTime, InputEvent};
+use parking_lot::Mutex;
+
+use crate::{Message, MessageReceiver, TICK_INTERVAL, hotplug::Device, osc::OscMessage, output::{RecordingBackend, Recorded}};
+use super::{ApiProvider, clock::TICKS_PER_BEAT, midi::{MidiTime, status_byte, to_message}};
+
+/// Sends a message straight through the dispatcher, the same way the
+/// dispatcher thread would.
+fn inject(l: &mlua::Lua, msg: Message) -> mlua::Result<()> {
+    crate::dispatch(l, &msg)
+}
+
+fn inject_midi(l: &mlua::Lua, data: &[u8]) -> mlua::Result<()> {
+    inject(l, to_message(data, MidiTime::now()))
+}
+
+fn device(backend: &RecordingBackend, idx: Option<usize>) -> mlua::Result<Arc<Mutex<Recorded>>> {
+    let idx = idx.unwrap_or(1);
+    let devices = backend.devices.lock();
+    idx.checked_sub(1)
+        .and_then(|x| devices.get(x))
+        .cloned()
+        .ok_or_else(|| mlua::Error::RuntimeError(format!("no device {} (only {} created)", idx, devices.len())))
+}
+
+/// Lets test scripts feed in MIDI and look at what came out the other end.
+pub struct Sim;
+impl ApiProvider for Sim {
+    /// The backend the script's devices are on, and what the script sends
+    /// the dispatcher.
+    type Arguments = (Arc<RecordingBackend>, MessageReceiver);
+
+    fn register_api(l: &mlua::Lua, args: Self::Arguments) -> anyhow::Result<()> {
+        let (backend, events) = args;
+        let events = Mutex::new(events);
+        let tab = l.create_table()?;
+
+        {
+            let midi = l.create_table()?;
+
+            midi.set("note_on", l.create_function(|l, (channel, key, vel): (u8, u8, Option<u8>)| {
+                inject_midi(l, &[status_byte(0x90, channel)?, key & 0x7f, vel.unwrap_or(127) & 0x7f])
+            })?)?;
+
+            midi.set("note_off", l.create_function(|l, (channel, key, vel): (u8, u8, Option<u8>)| {
+                inject_midi(l, &[status_byte(0x80, channel)?, key & 0x7f, vel.unwrap_or(0) & 0x7f])
+            })?)?;
+
+            midi.set("cc", l.create_function(|l, (channel, control, value): (u8, u8, u8)| {
+                inject_midi(l, &[status_byte(0xb0, channel)?, control & 0x7f, value & 0x7f])
+            })?)?;
+
+            // 14-bit value, 8192 is the centre
+            midi.set("pitch_bend", l.create_function(|l, (channel, value): (u8, u16)| {
+                let value = value.min(0x3fff);
+                inject_midi(l, &[status_byte(0xe0, channel)?, (value & 0x7f) as u8, (value >> 7) as u8])
+            })?)?;
+
+            midi.set("send", l.create_function(|l, (data,): (Vec<u8>,)| {
+                inject_midi(l, &data)
+            })?)?;
+
+            // Clock ticks, stamped as if they came `bpm` (120 by default) apart
+            {
+                let device_us = Mutex::new(0.0f64);
+                midi.set("clock", l.create_function(move |l, (ticks, bpm): (Option<u32>, Option<f64>)| {
+                    let per_tick = 60_000_000.0 / (bpm.unwrap_or(120.0) * TICKS_PER_BEAT as f64);
+                    for _ in 0..ticks.unwrap_or(1) {
+                        let device = {
+                            let mut us = device_us.lock();
+                            *us += per_tick;
+                            *us as u64
+                        };
+                        inject(l, to_message(&[0xf8], MidiTime { device, received: super::misc::now(), played: false }))?;
+                    }
+                    Ok(())
+                })?)?;
+            }
+
+            midi.set("start", l.create_function(|l, _: ()| inject_midi(l, &[0xfa]))?)?;
+            midi.set("stop", l.create_function(|l, _: ()| inject_midi(l, &[0xfc]))?)?;
+
+            tab.set("midi", midi)?;
+        }
+
+        // Input device events, `dev` is a device from evdev.open
+        {
+            let evdev = l.create_table()?;
+
+            evdev.set("key", l.create_function(|l, (dev, code, pressed): (mlua::Table, u16, bool)| {
+                let ev = InputEvent { time: EventTime::new(0, 0), kind: EventKind::Key, code, value: pressed as i32 };
+                inject(l, Message::Evdev(dev.get("id")?, ev, super::misc::now()))
+            })?)?;
+
+            // Raw value, simulated devices have a range of -32768..32767
+            evdev.set("axis", l.create_function(|l, (dev, code, value): (mlua::Table, u16, i32)| {
+                let ev = InputEvent { time: EventTime::new(0, 0), kind: EventKind::Absolute, code, value };
+                inject(l, Message::Evdev(dev.get("id")?, ev, super::misc::now()))
+            })?)?;
+
+            tab.set("evdev", evdev)?;
+        }
+
+        tab.set("osc", l.create_function(|l, (addr, args): (String, mlua::Variadic<mlua::Value>)| {
+            let args = args.into_iter().map(super::osc::lua_to_arg).collect::<mlua::Result<_>>()?;
+            inject(l, Message::Osc(OscMessage { addr, args }, super::misc::now()))
+        })?)?;
+
+        // Pretends a different application got focus
+        tab.set("focus", l.create_function(|l, (app_id, title): (String, Option<String>)| {
+            let title = title.unwrap_or_default();
+            crate::focus::set_current(&app_id, &title);
+            inject(l, Message::Focus { app_id, title })
+        })?)?;
+
+        // Pretends a device was plugged in or unplugged. kind is "midi" or "evdev"
+        let plug = |added| move |l: &mlua::Lua, (kind, name): (String, String)| {
+            let kind = match kind.as_str() {
+                "midi" => "midi",
+                "evdev" => "evdev",
+                x => return Err(mlua::Error::RuntimeError(format!("unknown device kind '{}'", x))),
+            };
+            inject(l, Message::Device { added, device: Device { kind, name, path: None, id: None } })
+        };
+        tab.set("device_added", l.create_function(plug(true))?)?;
+        tab.set("device_removed", l.create_function(plug(false))?)?;
+
+        // Lets time pass, running ticks and anything they send (like macro
+        // MIDI and clock). Time is simulated, so this doesn't actually wait
+        tab.set("advance", l.create_function(move |l, (secs,): (f32,)| {
+            let steps = (secs.max(0.0) / TICK_INTERVAL.as_secs_f32()).ceil() as u32;
+            for _ in 0..steps {
+                super::misc::advance(TICK_INTERVAL);
+                inject(l, Message::Tick)?;
+
+                loop {
+                    let msg = events.lock().try_recv();
+                    match msg {
+                        Ok(msg) => inject(l, msg)?,
+                        Err(_) => break,
+                    }
+                }
+            }
+            Ok(())
+        })?)?;
+
+        // Devices are numbered from 1 in the order they were created
+        {
+            let backend = backend.clone();
+            let pressed = l.create_function(move |_l, (code, dev): (u16, Option<usize>)| {
+                let dev = device(&backend, dev)?;
+                let pressed = dev.lock().keys.get(&code).copied().unwrap_or(0) != 0;
+                Ok(pressed)
+            })?;
+            tab.set("button", pressed.clone())?;
+            tab.set("key", pressed)?;
+        }
+
+        {
+            let backend = backend.clone();
+            tab.set("axis", l.create_function(move |_l, (code, dev): (u16, Option<usize>)| {
+                let dev = device(&backend, dev)?;
+                let value = dev.lock().abs.get(&code).copied().unwrap_or(0);
+                Ok(value as f32 / 32768.0)
+            })?)?;
+        }
+
+        // What a device has been sent since clear_events, up to the last 10000
+        {
+            let backend = backend.clone();
+            tab.set("events", l.create_function(move |l, (dev,): (Option<usize>,)| {
+                let dev = device(&backend, dev)?;
+                let dev = dev.lock();
+                let events = l.create_table()?;
+                for (i, ev) in dev.events.iter().filter(|x| x.kind != EventKind::Synchronize).enumerate() {
+                    let t = l.create_table()?;
+                    t.set("kind", format!("{:?}", ev.kind).to_lowercase())?;
+                    t.set("code", ev.code)?;
+                    t.set("value", ev.value)?;
+                    events.set(i + 1, t)?;
+                }
+                Ok(events)
+            })?)?;
+        }
+
+        tab.set("clear_events", l.create_function(move |_l, _: ()| {
+            for dev in backend.devices.lock().iter() {
+                dev.lock().events.clear();
+            }
+            Ok(())
+        })?)?;
+
+        l.globals().set("sim", tab)?;
+
+        // Tests are collected here and run by the harness
+        l.globals().set("_tests", l.create_table()?)?;
+        l.load(r#"
+            function test(name, f)
+                table.insert(_tests, {name = name, f = f})
+            end
+
+            function assert_eq(actual, expected, msg)
+                if actual ~= expected then
+                    error((msg and (msg .. ": ") or "") .. "expected " .. tostring(expected) .. ", got " .. tostring(actual), 2)
+                end
+            end
+
+            function assert_near(actual, expected, eps, msg)
+                eps = eps or 0.001
+                if math.abs(actual - expected) > eps then
+                    error((msg and (msg .. ": ") or "") .. "expected " .. tostring(expected) .. " +/- " .. tostring(eps) .. ", got " .. tostring(actual), 2)
+                end
+            end
+        "#).set_name("sim")?.exec()?;
+
+        Ok(())
+    }
+}
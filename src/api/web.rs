@@ -0,0 +1,30 @@
+use mlua::LuaSerdeExt;
+use serde_json::Value;
+
+use crate::web;
+use super::ApiProvider;
+
+/// Talks to browsers connected to the web server. Messages from them arrive
+/// at `on_web_recv(msg, client)`.
+pub struct Web;
+impl ApiProvider for Web {
+    type Arguments = ();
+
+    fn register_api(l: &mlua::Lua, _args: Self::Arguments) -> anyhow::Result<()> {
+        let tab = l.create_table()?;
+
+        tab.set("broadcast", l.create_function(|l, (value,): (mlua::Value,)| {
+            web::send(None, &l.from_value::<Value>(value)?);
+            Ok(())
+        })?)?;
+
+        tab.set("send", l.create_function(|l, (client, value): (u64, mlua::Value)| {
+            web::send(Some(client), &l.from_value::<Value>(value)?);
+            Ok(())
+        })?)?;
+
+        l.globals().set("web", tab)?;
+
+        Ok(())
+    }
+}
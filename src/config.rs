@@ -0,0 +1,255 @@
+use std::{collections::HashMap, path::Path};
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use crate::api::{
+    map::{self, Source, Output},
+    gamepad::PadHandle,
+    keyboard::KeyboardHandle,
+};
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// MIDI input port to open, like `midi.open()`.
+    pub midi: Option<usize>,
+    /// Created in the order they're written, which is how they're numbered.
+    #[serde(default)]
+    pub devices: IndexMap<String, Device>,
+    #[serde(default)]
+    pub bindings: Vec<Binding>,
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Device {
+    Gamepad {
+        /// `vendor:product` in hex, like `gamepad.create()`.
+        id: Option<String>,
+        #[serde(default)]
+        sticks: HashMap<String, StickSettings>,
+    },
+    Keyboard,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StickSettings {
+    pub deadzone: Option<f32>,
+    pub outer_deadzone: Option<f32>,
+    pub deadzone_mode: Option<String>,
+    pub curve: Option<String>,
+    pub exponent: Option<f32>,
+    pub anti_deadzone: Option<f32>,
+    pub square: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Binding {
+    pub layer: Option<String>,
+
+    // Source
+    pub channel: Option<i8>,
+    pub note: Option<u8>,
+    pub cc: Option<u8>,
+    #[serde(default)]
+    pub pitch_bend: bool,
+
+    // Output
+    pub device: Option<String>,
+    pub button: Option<String>,
+    pub axis: Option<String>,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    #[serde(default)]
+    pub invert: bool,
+    pub key: Option<String>,
+    pub momentary: Option<String>,
+    pub toggle: Option<String>,
+}
+
+/// A layer to switch on while an application has focus, like `focus.profile()`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub app: Option<String>,
+    pub title: Option<String>,
+    pub layer: String,
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Config> {
+    let text = std::fs::read_to_string(path)?;
+    let ext = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+
+    Ok(match ext {
+        "toml" => toml::from_str(&text)?,
+        "yaml" | "yml" => serde_yaml::from_str(&text)?,
+        _ => anyhow::bail!("Don't know how to read config {:?}, expected .toml or .yaml", path),
+    })
+}
+
+enum DeviceRef<'lua> {
+    Gamepad(mlua::Table<'lua>),
+    Keyboard(mlua::Table<'lua>),
+}
+
+/// Looks up `name` in one of the API constant tables, with or without its prefix.
+pub fn constant(api: &mlua::Table, prefix: &str, name: &str) -> anyhow::Result<i32> {
+    let name = name.to_uppercase();
+    let full = match name.starts_with(prefix) {
+        true => name,
+        false => format!("{}{}", prefix, name),
+    };
+
+    match api.get::<_, Option<i32>>(full.as_str())? {
+        Some(x) => Ok(x),
+        None => anyhow::bail!("Unknown name {}", full),
+    }
+}
+
+impl Binding {
+    fn source(&self) -> anyhow::Result<Source> {
+        let channel = self.channel;
+        match (self.note, self.cc, self.pitch_bend) {
+            (Some(key), None, false) => Ok(Source::Note { channel, key }),
+            (None, Some(control), false) => Ok(Source::Control { channel, control }),
+            (None, None, true) => Ok(Source::PitchBend { channel }),
+            _ => anyhow::bail!("Binding needs exactly one of `note`, `cc` or `pitch_bend`"),
+        }
+    }
+
+    fn output(&self, l: &mlua::Lua, devices: &HashMap<String, DeviceRef>) -> anyhow::Result<Output> {
+        if let Some(layer) = &self.momentary {
+            return Ok(Output::Momentary(layer.clone()));
+        }
+        if let Some(layer) = &self.toggle {
+            return Ok(Output::Toggle(layer.clone()));
+        }
+
+        let name = match &self.device {
+            Some(x) => x,
+            None => anyhow::bail!("Binding needs a `device`"),
+        };
+        let device = match devices.get(name) {
+            Some(x) => x,
+            None => anyhow::bail!("Unknown device '{}'", name),
+        };
+
+        let globals = l.globals();
+        match device {
+            DeviceRef::Gamepad(pad) => {
+                let api = globals.get::<_, mlua::Table>("gamepad")?;
+                let handle = pad.get::<_, mlua::AnyUserData>("_handle")?.borrow::<PadHandle>()?.clone();
+
+                match (&self.button, &self.axis) {
+                    (Some(btn), None) => Ok(Output::Button(handle, constant(&api, "BTN_", btn)?)),
+                    (None, Some(axis)) => {
+                        let (mut min, mut max) = (self.min.unwrap_or(-1.0), self.max.unwrap_or(1.0));
+                        if self.invert {
+                            std::mem::swap(&mut min, &mut max);
+                        }
+                        Ok(Output::Axis { pad: handle, axis: constant(&api, "AXIS_", axis)?, min, max })
+                    },
+                    _ => anyhow::bail!("Gamepad binding needs exactly one of `button` or `axis`"),
+                }
+            },
+            DeviceRef::Keyboard(kbd) => {
+                let api = globals.get::<_, mlua::Table>("keyboard")?;
+                let handle = kbd.get::<_, mlua::AnyUserData>("_handle")?.borrow::<KeyboardHandle>()?.clone();
+
+                match &self.key {
+                    Some(key) => Ok(Output::Key(handle, constant(&api, "KEY_", key)?)),
+                    None => anyhow::bail!("Keyboard binding needs a `key`"),
+                }
+            },
+        }
+    }
+}
+
+/// Sets up devices, MIDI ports and mappings from a config, using the same
+/// APIs a script would.
+pub fn apply(l: &mlua::Lua, cfg: &Config) -> anyhow::Result<()> {
+    let globals = l.globals();
+
+    let mut devices = HashMap::new();
+    for (name, dev) in cfg.devices.iter() {
+        match dev {
+            Device::Gamepad { id, sticks } => {
+                let create = globals.get::<_, mlua::Table>("gamepad")?.get::<_, mlua::Function>("create")?;
+                let pad = create.call::<_, mlua::Table>((id.clone(),))?;
+
+                for (stick, s) in sticks.iter() {
+                    let tab = l.create_table()?;
+                    tab.set("deadzone", s.deadzone)?;
+                    tab.set("outer_deadzone", s.outer_deadzone)?;
+                    tab.set("deadzone_mode", s.deadzone_mode.clone())?;
+                    tab.set("curve", s.curve.clone())?;
+                    tab.set("exponent", s.exponent)?;
+                    tab.set("anti_deadzone", s.anti_deadzone)?;
+                    tab.set("square", s.square)?;
+
+                    let configure = pad.get::<_, mlua::Function>("configure_stick")?;
+                    configure.call::<_, ()>((stick.as_str(), tab))?;
+                }
+
+                devices.insert(name.clone(), DeviceRef::Gamepad(pad));
+            },
+            Device::Keyboard => {
+                let create = globals.get::<_, mlua::Table>("keyboard")?.get::<_, mlua::Function>("create")?;
+                devices.insert(name.clone(), DeviceRef::Keyboard(create.call::<_, mlua::Table>(())?));
+            },
+        }
+        debug!("Created device '{}'", name);
+    }
+
+    for (i, b) in cfg.bindings.iter().enumerate() {
+        let layer = b.layer.as_deref().unwrap_or("base");
+        let source = b.source().map_err(|e| anyhow::anyhow!("Binding {}: {}", i + 1, e))?;
+        let output = b.output(l, &devices).map_err(|e| anyhow::anyhow!("Binding {}: {}", i + 1, e))?;
+        map::bind(l, layer, source, output);
+    }
+
+    for (i, p) in cfg.profiles.iter().enumerate() {
+        if p.app.is_none() && p.title.is_none() {
+            anyhow::bail!("Profile {}: needs an `app` or a `title`", i + 1);
+        }
+        let which = l.create_table()?;
+        which.set("app", p.app.clone())?;
+        which.set("title", p.title.clone())?;
+        let profile = globals.get::<_, mlua::Table>("focus")?.get::<_, mlua::Function>("profile")?;
+        profile.call::<_, ()>((which, p.layer.clone()))?;
+    }
+
+    if let Some(port) = cfg.midi {
+        let open = globals.get::<_, mlua::Table>("midi")?.get::<_, mlua::Function>("open")?;
+        open.call::<_, ()>((port,))?;
+    }
+
+    // Keep the device tables alive for as long as the Lua state is
+    let keep = l.create_table()?;
+    for (name, dev) in devices {
+        match dev {
+            DeviceRef::Gamepad(x) | DeviceRef::Keyboard(x) => keep.set(name, x)?,
+        }
+    }
+    l.set_named_registry_value("handcake_config_devices", keep)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_keep_their_order() {
+        let names = |cfg: Config| cfg.devices.keys().cloned().collect::<Vec<_>>();
+        let toml = "[devices.z]\ntype = \"keyboard\"\n[devices.a]\ntype = \"gamepad\"\n[devices.m]\ntype = \"keyboard\"\n";
+        assert_eq!(names(toml::from_str(toml).unwrap()), ["z", "a", "m"]);
+        let yaml = "devices:\n  z: { type: keyboard }\n  a: { type: gamepad }\n  m: { type: keyboard }\n";
+        assert_eq!(names(serde_yaml::from_str(yaml).unwrap()), ["z", "a", "m"]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::Instant;
+
+#[derive(Clone, Debug)]
+pub enum FilterKind {
+    /// Exponential moving average with a time constant in seconds.
+    Ema { tau: f32 },
+    /// https://gery.casiez.net/1euro/
+    OneEuro { min_cutoff: f32, beta: f32, d_cutoff: f32 },
+    /// Moves towards the input by at most `rate` units per second.
+    Slew { rate: f32 },
+    /// Only follows the input once it has moved further than `threshold`.
+    Hysteresis { threshold: f32 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Filter {
+    kind: FilterKind,
+    value: Option<f32>,
+    prev_input: f32,
+    dx: f32,
+    last: Option<Instant>,
+}
+
+fn smoothing(cutoff: f32, dt: f32) -> f32 {
+    let tau = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind) -> Self {
+        Self {
+            kind,
+            value: None,
+            prev_input: 0.0,
+            dx: 0.0,
+            last: None,
+        }
+    }
+
+    pub fn value(&self) -> Option<f32> {
+        self.value
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.kind.clone());
+    }
+
+    /// Feeds a sample into the filter, using the time since the last sample
+    /// (from `misc::now`, so simulated in tests) as the time step.
+    pub fn update(&mut self, x: f32) -> f32 {
+        let now = crate::api::misc::now();
+        let dt = match self.last {
+            Some(t) => now.duration_since(t).as_secs_f32(),
+            None => 0.0,
+        };
+        self.last = Some(now);
+        self.step(x, dt)
+    }
+
+    /// Like `update`, but starts from `initial` rather than snapping to the
+    /// first sample.
+    pub fn seed(&mut self, initial: f32) {
+        self.value = Some(initial);
+        self.prev_input = initial;
+        self.last = Some(crate::api::misc::now());
+    }
+
+    fn step(&mut self, x: f32, dt: f32) -> f32 {
+        let y = match self.value {
+            None => {
+                self.prev_input = x;
+                self.value = Some(x);
+                return x;
+            },
+            Some(y) => y,
+        };
+        if dt <= 0.0 {
+            return y;
+        }
+
+        let out = match self.kind {
+            FilterKind::Ema { tau } => {
+                if tau <= 0.0 {
+                    x
+                } else {
+                    y + (x - y) * (1.0 - (-dt / tau).exp())
+                }
+            },
+            FilterKind::OneEuro { min_cutoff, beta, d_cutoff } => {
+                let dx = (x - self.prev_input) / dt;
+                self.dx += (dx - self.dx) * smoothing(d_cutoff, dt);
+                let cutoff = min_cutoff + beta * self.dx.abs();
+                y + (x - y) * smoothing(cutoff, dt)
+            },
+            FilterKind::Slew { rate } => {
+                let max = rate * dt;
+                y + (x - y).clamp(-max, max)
+            },
+            FilterKind::Hysteresis { threshold } => {
+                if (x - y).abs() > threshold {
+                    x
+                } else {
+                    y
+                }
+            },
+        };
+
+        self.prev_input = x;
+        self.value = Some(out);
+        out
+    }
+}
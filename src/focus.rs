@@ -0,0 +1,276 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+use parking_lot::Mutex;
+use serde_json::Value;
+use x11rb::{
+    connection::Connection,
+    protocol::{Event, xproto::{AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window}},
+    rust_connection::RustConnection,
+};
+
+use crate::{Message, MessageSender};
+
+// Works out which application has focus and tells the scripts whenever that
+// changes. Sway and Hyprland are asked over their IPC sockets, anything else
+// with a $DISPLAY through _NET_ACTIVE_WINDOW. The app id is the Wayland
+// app_id, or the window class on X11.
+
+lazy_static::lazy_static! {
+    static ref CURRENT: Mutex<Option<(String, String)>> = Mutex::new(None);
+}
+
+/// The focused application's id and window title, if anything has focus.
+pub fn current() -> Option<(String, String)> {
+    CURRENT.lock().clone()
+}
+
+/// Forgets what has focus, so tests start from nothing.
+pub fn clear() {
+    *CURRENT.lock() = None;
+}
+
+/// Returns whether that's any different from before.
+pub fn set_current(app_id: &str, title: &str) -> bool {
+    let mut current = CURRENT.lock();
+    if current.as_ref().is_some_and(|(a, t)| a == app_id && t == title) {
+        return false;
+    }
+    *current = Some((app_id.into(), title.into()));
+    true
+}
+
+/// Sends a focus change, unless nothing actually changed. Returns false
+/// once nothing is listening.
+fn report(sender: &MessageSender, app_id: String, title: String) -> bool {
+    if !set_current(&app_id, &title) {
+        return true;
+    }
+    sender.send(Message::Focus { app_id, title }).is_ok()
+}
+
+// Sway, using the i3 IPC protocol
+
+const I3_MAGIC: &[u8] = b"i3-ipc";
+const I3_GET_TREE: u32 = 4;
+const I3_SUBSCRIBE: u32 = 2;
+const I3_WINDOW_EVENT: u32 = 0x80000003;
+
+fn i3_send(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> std::io::Result<()> {
+    let mut msg = I3_MAGIC.to_vec();
+    msg.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    msg.extend_from_slice(&kind.to_ne_bytes());
+    msg.extend_from_slice(payload);
+    stream.write_all(&msg)
+}
+
+fn i3_recv(stream: &mut UnixStream) -> anyhow::Result<(u32, Value)> {
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header)?;
+    if &header[..6] != I3_MAGIC {
+        anyhow::bail!("not an i3 IPC message");
+    }
+    let len = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+    let kind = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((kind, serde_json::from_slice(&payload)?))
+}
+
+/// App id and title of a sway container. XWayland windows have a class
+/// instead of an app id.
+fn sway_window(node: &Value) -> (String, String) {
+    let app_id = node["app_id"].as_str()
+        .or_else(|| node["window_properties"]["class"].as_str())
+        .unwrap_or_default();
+    (app_id.to_string(), node["name"].as_str().unwrap_or_default().to_string())
+}
+
+fn sway_focused(node: &Value) -> Option<&Value> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(node);
+    }
+    ["nodes", "floating_nodes"].iter()
+        .filter_map(|x| node[*x].as_array())
+        .flatten()
+        .find_map(sway_focused)
+}
+
+fn watch_sway(path: PathBuf, sender: MessageSender) -> anyhow::Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+
+    i3_send(&mut stream, I3_GET_TREE, b"")?;
+    let (_, tree) = i3_recv(&mut stream)?;
+    if let Some(node) = sway_focused(&tree) {
+        let (app_id, title) = sway_window(node);
+        report(&sender, app_id, title);
+    }
+
+    i3_send(&mut stream, I3_SUBSCRIBE, br#"["window"]"#)?;
+    loop {
+        let (kind, msg) = i3_recv(&mut stream)?;
+        if kind != I3_WINDOW_EVENT {
+            continue;
+        }
+        let container = &msg["container"];
+        let wanted = match msg["change"].as_str() {
+            Some("focus") => true,
+            Some("title") => container["focused"].as_bool() == Some(true),
+            _ => false,
+        };
+        if wanted {
+            let (app_id, title) = sway_window(container);
+            if !report(&sender, app_id, title) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Hyprland, reading events from its second socket
+
+fn hyprland_dir(signature: &str) -> PathBuf {
+    let runtime = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(|x| PathBuf::from(x).join("hypr").join(signature));
+    match runtime {
+        Some(x) if x.exists() => x,
+        _ => PathBuf::from("/tmp/hypr").join(signature),
+    }
+}
+
+fn watch_hyprland(dir: PathBuf, sender: MessageSender) -> anyhow::Result<()> {
+    // The first socket answers requests, which is the only way to find out
+    // what already has focus
+    if let Ok(mut stream) = UnixStream::connect(dir.join(".socket.sock")) {
+        stream.write_all(b"j/activewindow")?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        if let Ok(window) = serde_json::from_str::<Value>(&reply) {
+            let app_id = window["class"].as_str().unwrap_or_default().to_string();
+            let title = window["title"].as_str().unwrap_or_default().to_string();
+            report(&sender, app_id, title);
+        }
+    }
+
+    let stream = UnixStream::connect(dir.join(".socket2.sock"))?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        // activewindow>>class,title, where only the title can have commas
+        if let Some(window) = line.strip_prefix("activewindow>>") {
+            let (app_id, title) = window.split_once(',').unwrap_or((window, ""));
+            if !report(&sender, app_id.into(), title.into()) {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// X11
+
+struct X11Atoms {
+    active_window: u32,
+    net_wm_name: u32,
+    utf8_string: u32,
+}
+
+fn x11_property(conn: &RustConnection, window: Window, property: u32, kind: u32) -> Option<Vec<u8>> {
+    let reply = conn.get_property(false, window, property, kind, 0, 1024).ok()?.reply().ok()?;
+    Some(reply.value)
+}
+
+/// App id (the class half of WM_CLASS) and title of a window.
+fn x11_window(conn: &RustConnection, atoms: &X11Atoms, window: Window) -> (String, String) {
+    let class = x11_property(conn, window, AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into()).unwrap_or_default();
+    // WM_CLASS is the instance then the class, each ending in a nul
+    let mut parts = class.split(|x| *x == 0).filter(|x| !x.is_empty());
+    let instance = parts.next().unwrap_or_default();
+    let app_id = parts.next().unwrap_or(instance);
+
+    let title = x11_property(conn, window, atoms.net_wm_name, atoms.utf8_string)
+        .filter(|x| !x.is_empty())
+        .or_else(|| x11_property(conn, window, AtomEnum::WM_NAME.into(), AtomEnum::STRING.into()))
+        .unwrap_or_default();
+
+    (String::from_utf8_lossy(app_id).into(), String::from_utf8_lossy(&title).into())
+}
+
+fn watch_x11(sender: MessageSender) -> anyhow::Result<()> {
+    let (conn, screen) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen].root;
+    let atom = |name: &[u8]| -> anyhow::Result<u32> {
+        Ok(conn.intern_atom(false, name)?.reply()?.atom)
+    };
+    let atoms = X11Atoms {
+        active_window: atom(b"_NET_ACTIVE_WINDOW")?,
+        net_wm_name: atom(b"_NET_WM_NAME")?,
+        utf8_string: atom(b"UTF8_STRING")?,
+    };
+
+    let watch = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+    conn.change_window_attributes(root, &watch)?;
+    conn.flush()?;
+
+    let mut watched = None;
+    loop {
+        let active = x11_property(&conn, root, atoms.active_window, AtomEnum::WINDOW.into())
+            .and_then(|x| x.get(..4).map(|x| u32::from_ne_bytes(x.try_into().unwrap())))
+            .filter(|x| *x != 0);
+
+        // Watch the focused window too, so title changes come through
+        if active != watched {
+            if let Some(window) = active {
+                let _ = conn.change_window_attributes(window, &watch);
+                conn.flush()?;
+            }
+            watched = active;
+        }
+
+        let (app_id, title) = match active {
+            Some(window) => x11_window(&conn, &atoms, window),
+            None => (String::new(), String::new()),
+        };
+        if !report(&sender, app_id, title) {
+            return Ok(());
+        }
+
+        loop {
+            if let Event::PropertyNotify(ev) = conn.wait_for_event()? {
+                let focus = ev.window == root && ev.atom == atoms.active_window;
+                let title = Some(ev.window) == watched
+                    && (ev.atom == atoms.net_wm_name || ev.atom == u32::from(AtomEnum::WM_NAME));
+                if focus || title {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Starts watching whichever window system is running. Returns false if
+/// there isn't one to watch.
+pub fn watch(sender: MessageSender) -> bool {
+
+    let (name, watcher): (&str, Box<dyn FnOnce() -> anyhow::Result<()> + Send>) =
+        if let Some(path) = std::env::var_os("SWAYSOCK") {
+            ("sway", Box::new(move || watch_sway(path.into(), sender)))
+        } else if let Ok(signature) = std::env::var("HYPRLAND_INSTANCE_SIGNATURE") {
+            ("Hyprland", Box::new(move || watch_hyprland(hyprland_dir(&signature), sender)))
+        } else if std::env::var_os("DISPLAY").is_some() {
+            ("X11", Box::new(move || watch_x11(sender)))
+        } else {
+            return false;
+        };
+
+    info!("Watching window focus on {}", name);
+    std::thread::spawn(move || {
+        if let Err(e) = watcher() {
+            error!("Stopped watching window focus on {}: {}", name, e);
+        }
+    });
+
+    true
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{Message, MessageSender, api::{evdev, midi}};
+
+// Notices devices being plugged in and unplugged. Input devices come and go
+// from /dev/input, watched with inotify. MIDI ports are polled, since ALSA
+// only announces them to sequencer clients, which midir doesn't expose.
+
+/// Something that was plugged in or unplugged.
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// "midi" or "evdev".
+    pub kind: &'static str,
+    pub name: String,
+    /// The /dev/input node, for input devices.
+    pub path: Option<PathBuf>,
+    /// The handle from evdev.open, if a script had the device open.
+    pub id: Option<u32>,
+}
+
+impl Device {
+    pub fn to_table<'lua>(&self, l: &'lua mlua::Lua) -> mlua::Result<mlua::Table<'lua>> {
+        let tab = l.create_table()?;
+        tab.set("kind", self.kind)?;
+        tab.set("name", self.name.as_str())?;
+        tab.set("path", self.path.as_ref().map(|x| x.to_string_lossy().to_string()))?;
+        tab.set("id", self.id)?;
+        Ok(tab)
+    }
+}
+
+const INPUT_DIR: &str = "/dev/input";
+
+fn is_event_node(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"event")
+}
+
+/// Names of the input devices there are now, by path.
+fn scan_input() -> HashMap<PathBuf, String> {
+    let entries = match std::fs::read_dir(INPUT_DIR) {
+        Ok(x) => x,
+        Err(_) => return HashMap::new(),
+    };
+    entries
+        .filter_map(|x| x.ok())
+        .filter(|x| is_event_node(&x.file_name()))
+        .filter_map(|x| Some((x.path(), evdev::probe(&x.path())?)))
+        .collect()
+}
+
+fn watch_input(sender: MessageSender) -> anyhow::Result<()> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let dir = CString::new(INPUT_DIR)?;
+    // Nodes are created before udev gives them permissions, so opening one
+    // only works after its attributes change
+    let mask = libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_DELETE;
+    if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut known = scan_input();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if len < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let mut offset = 0;
+        while offset < len as usize {
+            let event = unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event) };
+            let start = offset + std::mem::size_of::<libc::inotify_event>();
+            offset = start + event.len as usize;
+
+            let name = buf[start..offset].split(|x| *x == 0).next().unwrap_or_default();
+            let name = OsStr::from_bytes(name);
+            if !is_event_node(name) {
+                continue;
+            }
+            let path = Path::new(INPUT_DIR).join(name);
+
+            let (added, device) = if event.mask & libc::IN_DELETE != 0 {
+                let name = match known.remove(&path) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let id = evdev::opened_id(&path);
+                (false, Device { kind: "evdev", name, path: Some(path), id })
+            } else {
+                if known.contains_key(&path) {
+                    continue;
+                }
+                let name = match evdev::probe(&path) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                known.insert(path.clone(), name.clone());
+                let id = evdev::reconnect(&path, sender.clone());
+                (true, Device { kind: "evdev", name, path: Some(path), id })
+            };
+
+            if sender.send(Message::Device { added, device }).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Starts watching for MIDI and input devices.
+pub fn watch(sender: MessageSender) {
+    let input = sender.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = watch_input(input) {
+            warn!("Not watching {} for devices: {}", INPUT_DIR, e);
+        }
+    });
+
+    midi::watch_ports(sender);
+}
@@ -0,0 +1,221 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::{fs::PermissionsExt, net::{UnixListener, UnixStream}},
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+use mlua::LuaSerdeExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{Message, MessageSender, api::{self, midi}, script::Scripts};
+
+// Each line sent to the socket is a JSON command like
+// `{"cmd": "set_layer", "layer": "keys", "active": true}`, and gets one line
+// back: `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`.
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Command {
+    /// Calls a global Lua function and returns its first result.
+    Call {
+        #[serde(rename = "fn")]
+        function: String,
+        #[serde(default)]
+        args: Vec<Value>,
+        script: Option<String>,
+    },
+    SetLayer { layer: String, active: bool, script: Option<String> },
+    Layers { script: Option<String> },
+    /// Reruns one script (or config) from scratch, or every running one.
+    Reload { script: Option<String> },
+    /// What the virtual devices are currently doing.
+    State,
+    /// Handles raw MIDI bytes as if they came from the controller.
+    Midi { data: Vec<u8> },
+    /// Every script, and whether it's running.
+    Scripts,
+    Enable { script: String },
+    /// Stops a script and throws away its devices until it's enabled again.
+    Disable { script: String },
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub command: Command,
+    pub reply: Sender<Result<Value, String>>,
+}
+
+/// `$XDG_RUNTIME_DIR/handcake.sock`, or somewhere in /tmp without it.
+pub fn default_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("handcake.sock"),
+        None => PathBuf::from(format!("/tmp/handcake-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+/// What the virtual devices are currently doing.
+pub fn state(scripts: &Scripts) -> Value {
+    let layers = scripts.running()
+        .map(|(name, l)| (name.to_string(), json!(api::map::active_layers(l))))
+        .collect::<serde_json::Map<_, _>>();
+    json!({
+        "gamepads": api::gamepad::states(scripts.running().map(|(_, l)| l)),
+        "keyboards": api::keyboard::pressed_keys(scripts.running().map(|(_, l)| l)),
+        "layers": layers,
+    })
+}
+
+/// The running script a command is for. Commands can leave the script out
+/// when only one is running.
+fn target<'a>(scripts: &'a Scripts, name: Option<&str>) -> Result<&'a mlua::Lua, String> {
+    match name {
+        Some(name) => scripts.running()
+            .find(|(x, _)| *x == name)
+            .map(|(_, l)| l)
+            .ok_or_else(|| format!("no running script called {}", name)),
+        None => {
+            let mut running = scripts.running();
+            match (running.next(), running.next()) {
+                (Some((_, l)), None) => Ok(l),
+                (None, _) => Err("no scripts are running".into()),
+                _ => Err("more than one script is running, say which with \"script\"".into()),
+            }
+        },
+    }
+}
+
+/// Runs a command against the scripts.
+pub fn handle(scripts: &mut Scripts, cmd: Command) -> Result<Value, String> {
+    fn lua_err(e: mlua::Error) -> String {
+        e.to_string()
+    }
+
+    match cmd {
+        Command::Call { function, args, script } => {
+            let l = target(scripts, script.as_deref())?;
+            let f = l.globals().get::<_, Option<mlua::Function>>(function.as_str()).map_err(lua_err)?
+                .ok_or_else(|| format!("no function called {}", function))?;
+            let args = args.iter()
+                .map(|x| l.to_value(x))
+                .collect::<mlua::Result<Vec<_>>>()
+                .map_err(lua_err)?;
+            let ret = f.call::<_, mlua::MultiValue>(mlua::MultiValue::from_vec(args)).map_err(lua_err)?;
+            match ret.into_iter().next() {
+                Some(x) => l.from_value::<Value>(x).map_err(lua_err),
+                None => Ok(Value::Null),
+            }
+        },
+        Command::SetLayer { layer, active, script } => {
+            let l = target(scripts, script.as_deref())?;
+            api::map::set_active(l, &layer, active);
+            Ok(json!(api::map::active_layers(l)))
+        },
+        Command::Layers { script } => Ok(json!(api::map::active_layers(target(scripts, script.as_deref())?))),
+        Command::State => Ok(state(scripts)),
+        Command::Midi { data } => {
+            // Every script gets it, even when one before it fails
+            let msg = midi::to_message(&data, midi::MidiTime::now());
+            let errors = scripts.running()
+                .filter_map(|(name, l)| crate::dispatch(l, &msg).err().map(|e| format!("{}: {}", name, e)))
+                .collect::<Vec<_>>();
+            match errors.is_empty() {
+                true => Ok(Value::Null),
+                false => Err(errors.join("; ")),
+            }
+        },
+        Command::Reload { script } => {
+            scripts.reload(script.as_deref()).map_err(|e| {
+                error!("Reload failed: {}", e);
+                e.to_string()
+            })?;
+            Ok(Value::Null)
+        },
+        Command::Scripts => Ok(scripts.scripts.iter()
+            .map(|x| json!({ "name": x.name, "enabled": x.lua.is_some() }))
+            .collect()),
+        Command::Enable { script } => {
+            scripts.enable(&script).map_err(|e| e.to_string())?;
+            Ok(Value::Null)
+        },
+        Command::Disable { script } => {
+            scripts.disable(&script)?;
+            Ok(Value::Null)
+        },
+    }
+}
+
+fn client(stream: UnixStream, sender: MessageSender) -> std::io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => {
+                let (reply, recv) = std::sync::mpsc::channel();
+                if sender.send(Message::Ipc(Request { command, reply })).is_err() {
+                    return Ok(());
+                }
+                recv.recv().unwrap_or_else(|_| Err("handcake is shutting down".into()))
+            },
+            Err(e) => Err(format!("bad command: {}", e)),
+        };
+
+        let response = match result {
+            Ok(x) => json!({ "ok": true, "result": x }),
+            Err(e) => json!({ "ok": false, "error": e }),
+        };
+        writeln!(out, "{}", response)?;
+    }
+
+    Ok(())
+}
+
+/// The socket commands come in on, which is removed when this is dropped.
+pub struct Socket(PathBuf);
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Starts accepting commands on a Unix socket, until the `Socket` is dropped.
+pub fn serve(path: &Path, sender: MessageSender) -> anyhow::Result<Socket> {
+    if path.exists() {
+        // Left over from a handcake that didn't shut down cleanly
+        if UnixStream::connect(path).is_ok() {
+            anyhow::bail!("something is already listening on {:?}", path);
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let socket = Socket(path.to_path_buf());
+    // Anyone who can connect can run Lua, so only this user gets to
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Accepting commands on {:?}", path);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Could not accept IPC connection: {}", e);
+                    continue;
+                },
+            };
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = client(stream, sender) {
+                    debug!("IPC client went away: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(socket)
+}
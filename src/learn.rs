@@ -0,0 +1,369 @@
+use std::{
+    fmt::Write as _,
+    io::{BufRead, Write},
+    path::Path,
+    sync::{Arc, mpsc::{Receiver, RecvTimeoutError, Sender}},
+    time::Duration,
+};
+use midi_control::MidiMessage;
+use midir::Ignore;
+
+use crate::{api::{ApiProvider, gamepad::Gamepad, keyboard::Keyboard, midi::find_port}, config, output::RecordingBackend, util};
+
+// `handcake learn`: asks for each control in turn, takes whatever MIDI comes
+// in next as its source, then writes the lot out as a script or config.
+
+/// Asked for when no controls are given.
+const DEFAULT_CONTROLS: &[&str] = &[
+    "BTN_A", "BTN_B", "BTN_X", "BTN_Y", "BTN_LB", "BTN_RB", "BTN_START", "BTN_SELECT",
+    "AXIS_LSTICK_X", "AXIS_LSTICK_Y",
+];
+
+/// After a control is learned, MIDI is ignored until it's been quiet this
+/// long, so letting go of a pad or finishing turning a knob doesn't count
+/// for the next one.
+const SETTLE_TIME: Duration = Duration::from_millis(400);
+
+enum Input {
+    Midi(MidiMessage),
+    /// A line typed on stdin.
+    Line(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Note { channel: i8, key: u8 },
+    Cc { channel: i8, control: u8 },
+    PitchBend { channel: i8 },
+}
+
+impl Source {
+    fn from_midi(midi: &MidiMessage) -> Option<Self> {
+        match midi {
+            MidiMessage::NoteOn(ch, k) if k.value > 0 => Some(Source::Note { channel: util::midi_channel_to_num(ch), key: k.key }),
+            MidiMessage::ControlChange(ch, cc) => Some(Source::Cc { channel: util::midi_channel_to_num(ch), control: cc.control }),
+            MidiMessage::PitchBend(ch, ..) => Some(Source::PitchBend { channel: util::midi_channel_to_num(ch) }),
+            _ => None,
+        }
+    }
+
+    fn channel(&self) -> i8 {
+        match self {
+            Source::Note { channel, .. } | Source::Cc { channel, .. } | Source::PitchBend { channel } => *channel,
+        }
+    }
+
+    /// The same control on any channel.
+    fn without_channel(&self) -> Self {
+        match *self {
+            Source::Note { key, .. } => Source::Note { channel: 0, key },
+            Source::Cc { control, .. } => Source::Cc { channel: 0, control },
+            Source::PitchBend { .. } => Source::PitchBend { channel: 0 },
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Source::Note { channel, key } => format!("note {} on channel {}", key, channel),
+            Source::Cc { channel, control } => format!("CC {} on channel {}", control, channel),
+            Source::PitchBend { channel } => format!("pitch bend on channel {}", channel),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Button,
+    Axis,
+    Key,
+}
+
+/// Something on a virtual device to learn a source for.
+struct Control {
+    kind: Kind,
+    /// The full constant name, like BTN_A.
+    name: String,
+}
+
+impl Control {
+    /// Works out what `name` is from the gamepad and keyboard constants. The
+    /// prefix can be left off, so "A" is BTN_A and "ENTER" is KEY_ENTER.
+    fn parse(gamepad: &mlua::Table, keyboard: &mlua::Table, name: &str) -> anyhow::Result<Self> {
+        let name = name.to_uppercase();
+        for (kind, api, prefix) in [(Kind::Button, gamepad, "BTN_"), (Kind::Axis, gamepad, "AXIS_"), (Kind::Key, keyboard, "KEY_")] {
+            let full = match name.starts_with(prefix) {
+                true => name.clone(),
+                false => format!("{}{}", prefix, name),
+            };
+            if config::constant(api, prefix, &full).is_ok() {
+                return Ok(Self { kind, name: full });
+            }
+        }
+        anyhow::bail!("{} isn't a gamepad button or axis, or a key", name)
+    }
+
+    /// The name without BTN_ and so on, the way configs write it.
+    fn short_name(&self) -> &str {
+        self.name.split_once('_').map(|(_, x)| x).unwrap_or(&self.name)
+    }
+}
+
+fn parse_controls(names: &[String]) -> anyhow::Result<Vec<Control>> {
+    // The constants live in the Lua APIs, so borrow them from a throwaway state
+    let lua = mlua::Lua::new();
+    let backend = Arc::new(RecordingBackend::new(false));
+    Gamepad::register_api(&lua, (backend.clone(),))?;
+    Keyboard::register_api(&lua, (backend,))?;
+    let gamepad = lua.globals().get::<_, mlua::Table>("gamepad")?;
+    let keyboard = lua.globals().get::<_, mlua::Table>("keyboard")?;
+
+    let names = match names.is_empty() {
+        true => DEFAULT_CONTROLS.iter().map(|x| x.to_string()).collect(),
+        false => names.to_vec(),
+    };
+    names.iter().map(|x| Control::parse(&gamepad, &keyboard, x)).collect()
+}
+
+/// Throws away MIDI until nothing has come in for a while. Returns false if
+/// the inputs went away.
+fn settle(inputs: &Receiver<Input>) -> bool {
+    loop {
+        match inputs.recv_timeout(SETTLE_TIME) {
+            Ok(Input::Midi(_)) => continue,
+            // Anything typed while waiting is for the next prompt
+            Ok(Input::Line(_)) => continue,
+            Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+/// Asks for each control in turn. Returns what was learned, in order.
+fn ask(controls: Vec<Control>, inputs: &Receiver<Input>) -> Vec<(Source, Control)> {
+    let mut learned: Vec<(Source, Control)> = vec![];
+
+    'controls: for control in controls {
+        print!("Press or move the control for {} (Enter to skip, q to finish): ", control.name);
+        let _ = std::io::stdout().flush();
+
+        loop {
+            let source = match inputs.recv() {
+                Ok(Input::Midi(x)) => match Source::from_midi(&x) {
+                    Some(x) => x,
+                    None => continue,
+                },
+                Ok(Input::Line(x)) if x.trim().eq_ignore_ascii_case("q") => {
+                    println!();
+                    break 'controls;
+                },
+                Ok(Input::Line(_)) => {
+                    println!("skipped");
+                    continue 'controls;
+                },
+                Err(_) => break 'controls,
+            };
+
+            if let Some((_, other)) = learned.iter().find(|(x, _)| *x == source) {
+                print!("\n  {} is already {}, try another: ", source.describe(), other.name);
+                let _ = std::io::stdout().flush();
+                if !settle(inputs) {
+                    break 'controls;
+                }
+                continue;
+            }
+
+            println!("{}", source.describe());
+            learned.push((source, control));
+            if !settle(inputs) {
+                break 'controls;
+            }
+            continue 'controls;
+        }
+    }
+
+    learned
+}
+
+/// Channels only get written out when two controls differ by nothing else.
+fn needs_channels(learned: &[(Source, Control)]) -> bool {
+    learned.iter().enumerate().any(|(i, (a, _))| {
+        learned[i + 1..].iter().any(|(b, _)| a.without_channel() == b.without_channel())
+    })
+}
+
+fn write_lua(learned: &[(Source, Control)], port: usize, from: &str) -> String {
+    let channels = needs_channels(learned);
+    let has = |kind| learned.iter().any(|(_, x)| x.kind == kind);
+    let pad = has(Kind::Button) || has(Kind::Axis);
+    let kbd = has(Kind::Key);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "-- Made with `handcake learn` from {}", from);
+    out.push('\n');
+    if pad {
+        out.push_str("local pad = nil\n");
+    }
+    if kbd {
+        out.push_str("local kbd = nil\n");
+    }
+    out.push('\n');
+    out.push_str("function on_script_init()\n");
+    let _ = writeln!(out, "    midi.open({})", port);
+    if pad {
+        out.push_str("    pad = gamepad.create()\n");
+    }
+    if kbd {
+        out.push_str("    kbd = keyboard.create()\n");
+    }
+    out.push('\n');
+    out.push_str("    local base = map.layer(map.BASE)\n");
+
+    for (source, control) in learned {
+        let ch = match channels {
+            true => format!(", {}", source.channel()),
+            false => String::new(),
+        };
+        let source = match source {
+            Source::Note { key, .. } => format!("map.note({}{})", key, ch),
+            Source::Cc { control, .. } => format!("map.cc({}{})", control, ch),
+            Source::PitchBend { .. } => format!("map.pitch_bend({})", ch.trim_start_matches(", ")),
+        };
+        let output = match control.kind {
+            Kind::Button => format!("map.button(pad, gamepad.{})", control.name),
+            Kind::Axis => format!("map.axis(pad, gamepad.{})", control.name),
+            Kind::Key => format!("map.key(kbd, keyboard.{})", control.name),
+        };
+        let _ = writeln!(out, "    base.bind({}, {})", source, output);
+    }
+    out.push_str("end\n");
+
+    out
+}
+
+/// One binding's fields as (key, value) pairs, already formatted as values.
+fn binding_fields(source: &Source, control: &Control, channels: bool) -> Vec<(&'static str, String)> {
+    let mut fields = vec![];
+    if channels {
+        fields.push(("channel", source.channel().to_string()));
+    }
+    match source {
+        Source::Note { key, .. } => fields.push(("note", key.to_string())),
+        Source::Cc { control, .. } => fields.push(("cc", control.to_string())),
+        Source::PitchBend { .. } => fields.push(("pitch_bend", "true".into())),
+    }
+    let (device, field) = match control.kind {
+        Kind::Button => ("pad", "button"),
+        Kind::Axis => ("pad", "axis"),
+        Kind::Key => ("kbd", "key"),
+    };
+    fields.push(("device", format!("\"{}\"", device)));
+    fields.push((field, format!("\"{}\"", control.short_name())));
+    fields
+}
+
+fn write_toml(learned: &[(Source, Control)], port: usize, from: &str, path: &Path) -> String {
+    let channels = needs_channels(learned);
+    let has = |kind| learned.iter().any(|(_, x)| x.kind == kind);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# Made with `handcake learn` from {}", from);
+    let _ = writeln!(out, "# Run with `handcake --config {}`", path.display());
+    out.push('\n');
+    let _ = writeln!(out, "midi = {}", port);
+
+    if has(Kind::Button) || has(Kind::Axis) {
+        out.push_str("\n[devices.pad]\ntype = \"gamepad\"\n");
+    }
+    if has(Kind::Key) {
+        out.push_str("\n[devices.kbd]\ntype = \"keyboard\"\n");
+    }
+
+    for (source, control) in learned {
+        out.push_str("\n[[bindings]]\n");
+        for (key, value) in binding_fields(source, control, channels) {
+            let _ = writeln!(out, "{} = {}", key, value);
+        }
+    }
+
+    out
+}
+
+fn write_yaml(learned: &[(Source, Control)], port: usize, from: &str, path: &Path) -> String {
+    let channels = needs_channels(learned);
+    let has = |kind| learned.iter().any(|(_, x)| x.kind == kind);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# Made with `handcake learn` from {}", from);
+    let _ = writeln!(out, "# Run with `handcake --config {}`", path.display());
+    out.push('\n');
+    let _ = writeln!(out, "midi: {}", port);
+
+    out.push_str("\ndevices:\n");
+    if has(Kind::Button) || has(Kind::Axis) {
+        out.push_str("  pad:\n    type: gamepad\n");
+    }
+    if has(Kind::Key) {
+        out.push_str("  kbd:\n    type: keyboard\n");
+    }
+
+    out.push_str("\nbindings:\n");
+    for (source, control) in learned {
+        for (i, (key, value)) in binding_fields(source, control, channels).into_iter().enumerate() {
+            let _ = writeln!(out, "{}{}: {}", if i == 0 { "  - " } else { "    " }, key, value);
+        }
+    }
+
+    out
+}
+
+/// Learns a mapping from MIDI port `port` and writes it to `out`. What gets
+/// written depends on the extension: a Lua script, or a TOML or YAML config.
+pub fn run(port: usize, out: &Path, controls: &[String], force: bool) -> anyhow::Result<()> {
+    let ext = out.extension().and_then(|x| x.to_str()).unwrap_or("");
+    if !matches!(ext, "lua" | "toml" | "yaml" | "yml") {
+        anyhow::bail!("Don't know how to write {:?}, expected .lua, .toml or .yaml", out);
+    }
+    if out.exists() && !force {
+        anyhow::bail!("{:?} already exists, pass --force to overwrite it", out);
+    }
+    let controls = parse_controls(controls)?;
+
+    let (sender, inputs): (Sender<Input>, Receiver<Input>) = std::sync::mpsc::channel();
+
+    let mut midi_in = midir::MidiInput::new("handcake MIDI learn")?;
+    midi_in.ignore(Ignore::All);
+    let midi_port = find_port(&midi_in, port)?;
+    let name = midi_in.port_name(&midi_port)?;
+    let _conn = midi_in.connect(&midi_port, &name, |_ts, data, sender| {
+        let _ = sender.send(Input::Midi(util::bytes_to_midi(data)));
+    }, sender.clone()).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    // Nothing waits on this, it's blocked reading until the process ends
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(x) => x,
+                Err(_) => break,
+            };
+            if sender.send(Input::Line(line)).is_err() {
+                break;
+            }
+        }
+    });
+
+    println!("Learning from {}", name);
+    let learned = ask(controls, &inputs);
+    if learned.is_empty() {
+        anyhow::bail!("Nothing was learned, not writing {:?}", out);
+    }
+
+    let text = match ext {
+        "lua" => write_lua(&learned, port, &name),
+        "toml" => write_toml(&learned, port, &name, out),
+        _ => write_yaml(&learned, port, &name, out),
+    };
+    std::fs::write(out, text)?;
+    println!("Wrote {} bindings to {:?}", learned.len(), out);
+
+    Ok(())
+}
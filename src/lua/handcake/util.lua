@@ -0,0 +1,198 @@
+-- Helpers shared by scripts, loaded with
+--     local util = require("handcake.util")
+
+local util = {}
+
+-- Notes
+
+local STEPS = {C = 0, D = 2, E = 4, F = 5, G = 7, A = 9, B = 11}
+local NAMES = {"C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"}
+
+-- MIDI note number from a name like "C4" (60), "F#2" or "Bb-1". Sharps are
+-- "#", flats are "b", and numbers are passed straight through.
+function util.note(name)
+    if type(name) == "number" then
+        return name
+    end
+
+    local letter, accidentals, octave = tostring(name):match("^([A-Ga-g])([#b]*)(-?%d+)$")
+    if not letter then
+        error(("not a note name: %q"):format(tostring(name)), 2)
+    end
+
+    local n = (tonumber(octave) + 1) * 12 + STEPS[letter:upper()]
+    for c in accidentals:gmatch(".") do
+        n = n + (c == "#" and 1 or -1)
+    end
+    if n < 0 or n > 127 then
+        error(("note %s is out of range"):format(name), 2)
+    end
+
+    return n
+end
+
+-- The name of a MIDI note number, using sharps. 60 is "C4".
+function util.note_name(n)
+    return NAMES[n % 12 + 1] .. (n // 12 - 1)
+end
+
+-- Numbers
+
+function util.clamp(x, lo, hi)
+    return math.max(lo, math.min(hi, x))
+end
+
+function util.lerp(a, b, t)
+    return a + (b - a) * t
+end
+
+-- Maps x from in_lo..in_hi onto out_lo..out_hi, clamped to the output range.
+function util.scale(x, in_lo, in_hi, out_lo, out_hi)
+    local t = (x - in_lo) / (in_hi - in_lo)
+    return util.lerp(out_lo, out_hi, util.clamp(t, 0, 1))
+end
+
+-- Curves
+--
+-- Each of these makes a function from a value in -1..1 (or 0..1) to
+-- another, keeping the sign, so they work on sticks and triggers alike.
+
+util.curves = {}
+
+function util.curves.linear()
+    return function(x) return x end
+end
+
+-- Above 1 is gentler near the middle, below 1 is more sensitive.
+function util.curves.power(gamma)
+    return function(x)
+        local sign = x < 0 and -1 or 1
+        return sign * math.abs(x) ^ gamma
+    end
+end
+
+-- Anything within `size` of the middle is 0, and the rest is stretched to
+-- still reach 1.
+function util.curves.deadzone(size)
+    return function(x)
+        local mag = math.abs(x)
+        if mag <= size then
+            return 0
+        end
+        local sign = x < 0 and -1 or 1
+        return sign * (mag - size) / (1 - size)
+    end
+end
+
+-- Smoothstep, eases in and out of the ends.
+function util.curves.smooth()
+    return function(x)
+        local sign = x < 0 and -1 or 1
+        local t = math.abs(x)
+        return sign * t * t * (3 - 2 * t)
+    end
+end
+
+-- Applies curves left to right.
+function util.chain(...)
+    local fs = {...}
+    return function(x)
+        for _, f in ipairs(fs) do
+            x = f(x)
+        end
+        return x
+    end
+end
+
+-- Mapping
+
+-- Binds notes by name or number to outputs in a layer:
+--     util.bind_notes(map.layer(map.BASE), {
+--         C4 = map.button(pad, gamepad.BTN_A),
+--         ["C#4"] = map.button(pad, gamepad.BTN_B),
+--     })
+function util.bind_notes(layer, bindings, channel)
+    for note, output in pairs(bindings) do
+        layer.bind(map.note(util.note(note), channel), output)
+    end
+end
+
+-- Binds control numbers to outputs in a layer.
+function util.bind_ccs(layer, bindings, channel)
+    for control, output in pairs(bindings) do
+        layer.bind(map.cc(control, channel), output)
+    end
+end
+
+-- A map.macro output that sends a curved value to a gamepad axis, for
+-- when map.axis's straight line isn't enough.
+function util.curved_axis(pad, axis, curve)
+    return map.macro(function(value)
+        pad.axis(axis, curve(value * 2 - 1))
+    end)
+end
+
+-- State machines
+--
+--     local mode = util.state_machine {
+--         initial = "idle",
+--         states = {
+--             idle = { on = { press = "armed" } },
+--             armed = {
+--                 enter = function(sm) print("armed") end,
+--                 on = { press = "idle", timeout = "idle" },
+--             },
+--         },
+--     }
+--     mode:send("press")
+--
+-- An event can also go to a function, which returns the state to go to (or
+-- nil to stay put). enter and exit get the machine and whatever was sent
+-- along with the event.
+
+local Machine = {}
+Machine.__index = Machine
+
+function util.state_machine(def)
+    if type(def) ~= "table" or type(def.states) ~= "table" or not def.states[def.initial] then
+        error("a state machine needs states and an initial state", 2)
+    end
+
+    local sm = setmetatable({ states = def.states, state = def.initial }, Machine)
+    local first = sm.states[sm.state]
+    if first.enter then
+        first.enter(sm)
+    end
+    return sm
+end
+
+-- Handles an event, returning whether the state changed.
+function Machine:send(event, ...)
+    local current = self.states[self.state]
+    local to = current.on and current.on[event]
+    if type(to) == "function" then
+        to = to(self, ...)
+    end
+    if to == nil then
+        return false
+    end
+    if not self.states[to] then
+        error(("no state called %q"):format(tostring(to)), 2)
+    end
+
+    if current.exit then
+        current.exit(self, ...)
+    end
+    self.state = to
+    local next = self.states[to]
+    if next.enter then
+        next.enter(self, ...)
+    end
+    return true
+end
+
+function Machine:is(state)
+    return self.state == state
+end
+
+return util
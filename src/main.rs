@@ -1,20 +1,150 @@
 pub mod api;
 mod util;
+mod stick;
+mod filter;
+mod config;
+mod modifier;
+mod session;
+mod output;
+mod testing;
+mod osc;
+mod ipc;
+mod script;
+mod sandbox;
+mod focus;
+mod hotplug;
+mod stats;
+mod learn;
+#[cfg(feature = "web")]
+mod web;
 
-use std::{path::{PathBuf, Path}, os::unix::prelude::OpenOptionsExt, sync::{Arc, mpsc::{Sender, Receiver}}};
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration};
 use clap::Parser;
 use midi_control::MidiMessage;
-use parking_lot::Mutex;
+use mlua::LuaSerdeExt;
 
-use crate::api::ApiProvider;
+use crate::{api::ApiProvider, output::{OutputBackend, UInputBackend, RecordingBackend}, script::Scripts};
 
 #[macro_use]
 extern crate log;
 
 #[derive(Parser)]
+#[clap(subcommand_negates_reqs = true)]
 struct HandcakeApplication {
-    #[clap(short='s',long="--script")]
-    pub script: PathBuf,
+    #[clap(flatten)]
+    pub run: RunArgs,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Args, Clone, Default)]
+struct RunArgs {
+    /// Script to run, or a directory of them. Can be given more than once,
+    /// each script gets its own Lua state
+    #[clap(short='s',long="--script", required_unless_present="config", conflicts_with="config")]
+    pub script: Vec<PathBuf>,
+
+    /// Run a TOML or YAML mapping file instead of a script
+    #[clap(short='c',long="--config")]
+    pub config: Option<PathBuf>,
+
+    /// Log output events instead of creating real devices
+    #[clap(long="dry-run")]
+    pub dry_run: bool,
+
+    /// Accept JSON commands on a Unix socket, $XDG_RUNTIME_DIR/handcake.sock by default
+    #[clap(long="socket")]
+    pub socket: Option<Option<PathBuf>>,
+
+    /// Run scripts without access to files, other programs or the
+    /// environment, with instruction and memory limits
+    #[clap(long="sandbox")]
+    pub sandbox: bool,
+
+    /// Lua instructions a script can run per event before it's stopped
+    #[clap(long="max-instructions")]
+    pub max_instructions: Option<u64>,
+
+    /// Memory each script can use, in MiB
+    #[clap(long="max-memory")]
+    pub max_memory: Option<usize>,
+
+    /// Measure how long MIDI takes to come out of a virtual device, and log
+    /// percentiles every 10 seconds and on exit
+    #[clap(long="stats")]
+    pub stats: bool,
+
+    /// Dispatch with realtime (SCHED_FIFO) priority and lock memory, for the
+    /// lowest latency. Needs CAP_SYS_NICE and CAP_IPC_LOCK or rtprio and
+    /// memlock limits
+    #[clap(long="realtime")]
+    pub realtime: bool,
+
+    /// Serve a web control surface on this address, like 0.0.0.0:8080
+    #[clap(long="http")]
+    pub http: Option<SocketAddr>,
+
+    /// Serve files from this directory instead of the built-in page
+    #[clap(long="web-root", requires="http")]
+    pub web_root: Option<PathBuf>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Record incoming MIDI to a session log
+    Record {
+        #[clap(short='o',long="out")]
+        out: PathBuf,
+
+        /// MIDI port to record from
+        #[clap(short='p',long="port",default_value="0")]
+        port: usize,
+    },
+
+    /// Run a script against a recorded session log instead of live MIDI, in
+    /// simulated time so it goes as fast as it can
+    Replay {
+        log: PathBuf,
+
+        #[clap(flatten)]
+        run: RunArgs,
+
+        /// Playback speed multiplier, as the script sees it
+        #[clap(long="speed",default_value="1.0")]
+        speed: f32,
+    },
+
+    /// Build a mapping by pressing each control on a MIDI controller in turn
+    Learn {
+        /// Where to write it: a .lua script, or a .toml or .yaml config
+        #[clap(short='o',long="out")]
+        out: PathBuf,
+
+        /// MIDI port to learn from
+        #[clap(short='p',long="port",default_value="0")]
+        port: usize,
+
+        /// Overwrite the output if it already exists
+        #[clap(long="force")]
+        force: bool,
+
+        /// Controls to learn, like BTN_A, AXIS_LSTICK_X or KEY_ENTER. The
+        /// face buttons, shoulders, start, select and left stick by default
+        controls: Vec<String>,
+    },
+
+    /// Run Lua test files against a script, with simulated MIDI input
+    Test {
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+
+        #[clap(short='s',long="script", conflicts_with="config")]
+        script: Option<PathBuf>,
+
+        #[clap(short='c',long="config")]
+        config: Option<PathBuf>,
+    },
 }
 
 #[cfg(not(unix))]
@@ -34,141 +164,374 @@ macro_rules! fatal_error {
 
 #[derive(Debug)]
 pub enum Message {
-    Midi(MidiMessage),
+    Midi(MidiMessage, api::midi::MidiTime),
+    /// MIDI clock and transport, which aren't `MidiMessage`s.
+    Clock(api::clock::ClockEvent, api::midi::MidiTime),
+    /// An event from an input device opened with `evdev.open`, by device id,
+    /// and when it was read.
+    Evdev(u32, input_linux::InputEvent, std::time::Instant),
+    Osc(osc::OscMessage, std::time::Instant),
+    Ipc(ipc::Request),
+    /// A different application (or window title) has focus.
+    Focus { app_id: String, title: String },
+    /// Something one script published for the others, by script id.
+    Bus { from: u32, topic: String, data: serde_json::Value },
+    /// A device was plugged in or unplugged.
+    Device { added: bool, device: hotplug::Device },
+    /// JSON from a browser, by client id.
+    #[cfg(feature = "web")]
+    Web(u64, serde_json::Value),
+    Tick,
+    Quit,
 }
 
-type MessageSender = Arc<Mutex<Sender<Message>>>;
-type MessageReceiver = Arc<Mutex<Receiver<Message>>>;
+/// How often time-based processing (filters etc.) runs.
+const TICK_INTERVAL: Duration = Duration::from_millis(4);
 
-lazy_static::lazy_static! {
-    static ref MESSAGE: (MessageSender, MessageReceiver) = {
-        let (send, recv) = std::sync::mpsc::channel();
+/// Everything that isn't a tick reaches the dispatcher through one of these:
+/// MIDI callbacks, device threads, IPC clients and so on each hold a clone.
+/// Sending never blocks, so it works from plain threads as well as tasks.
+pub type MessageSender = tokio::sync::mpsc::UnboundedSender<Message>;
+pub type MessageReceiver = tokio::sync::mpsc::UnboundedReceiver<Message>;
 
-        (Arc::new(Mutex::new(send)), Arc::new(Mutex::new(recv)))
-    };
+/// `dir` is where the script is, for `require` to look in.
+fn register_apis(lua: &mlua::Lua, backend: Arc<dyn OutputBackend>, dir: Option<PathBuf>) {
+    api::midi::Midi::register_api(lua, ()).unwrap();
+    api::gamepad::Gamepad::register_api(lua, (backend.clone(),)).unwrap();
+    api::misc::Misc::register_api(lua, ()).unwrap();
+    api::filter::Filters::register_api(lua, ()).unwrap();
+    api::keyboard::Keyboard::register_api(lua, (backend,)).unwrap();
+    api::map::Map::register_api(lua, ()).unwrap();
+    api::macros::Macros::register_api(lua, ()).unwrap();
+    api::evdev::Evdev::register_api(lua, ()).unwrap();
+    api::osc::Osc::register_api(lua, ()).unwrap();
+    api::bus::Bus::register_api(lua, ()).unwrap();
+    api::focus::Focus::register_api(lua, ()).unwrap();
+    api::clock::Clocks::register_api(lua, ()).unwrap();
+    api::package::Package::register_api(lua, (dir,)).unwrap();
+    #[cfg(feature = "web")]
+    api::web::Web::register_api(lua, ()).unwrap();
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    if cfg!(debug_assertions) {
-        pretty_env_logger::formatted_builder().filter_level(log::LevelFilter::Debug).init();
+fn output_backend(run: &RunArgs) -> Arc<dyn OutputBackend> {
+    if run.dry_run {
+        info!("Dry run, not creating any real devices");
+        Arc::new(RecordingBackend::new(true))
     } else {
-        pretty_env_logger::init();
+        if !Path::new("/dev/uinput").exists() {
+            fatal_error!("Could not find /dev/uinput. Is uinput installed?");
+        }
+        Arc::new(UInputBackend)
     }
+}
 
-    let cli = HandcakeApplication::parse();
-    let script_path = cli.script;
-    info!("handcake v{} starting - (c)2022 rin", env!("CARGO_PKG_VERSION"));
-    if !script_path.exists() {
-        fatal_error!("Script at path {:?} does not exist, aborting.", script_path);
+/// Hands one message to a script. Messages that aren't for any one script
+/// are left to `Scripts::handle`.
+fn dispatch(lua: &mlua::Lua, msg: &Message) -> mlua::Result<()> {
+    match msg {
+        Message::Tick => {
+            api::gamepad::tick(lua)?;
+            api::macros::tick(lua)?;
+        },
+        Message::Midi(midi, time) => {
+            if !time.played {
+                api::macros::record_midi(lua, midi);
+            }
+            api::map::handle_midi(lua, midi)?;
+
+            let on_midi_recv = lua.globals().get::<&str, mlua::Function>("on_midi_recv");
+            if on_midi_recv.is_err() {
+                return Ok(());
+            }
+            let on_midi_recv = on_midi_recv.unwrap();
+            if let MidiMessage::Invalid = midi {
+                return Ok(());
+            }
+
+            let tab = lua.create_table()?;
+            // The device's own time, and when handcake got it on the misc.now_ns() clock
+            tab.set("timestamp_us", time.device)?;
+            tab.set("received_ns", api::misc::nanos_since_start(time.received))?;
+
+            match midi {
+                MidiMessage::NoteOn(channel, key) => {
+                    tab.set("event", "note_on")?;
+                    tab.set("channel", util::midi_channel_to_num(channel))?;
+                    tab.set("key", key.key)?;
+                    tab.set("vel", key.value)?;
+                    tab.set("is_note", true)?;
+                },
+                MidiMessage::NoteOff(channel, key) => {
+                    tab.set("event", "note_off")?;
+                    tab.set("channel", util::midi_channel_to_num(channel))?;
+                    tab.set("key", key.key)?;
+                    tab.set("vel", key.value)?;
+                    tab.set("is_note", true)?;
+                },
+                MidiMessage::ControlChange(channel, cc) => {
+                    tab.set("event", "control_change")?;
+                    tab.set("channel", util::midi_channel_to_num(channel))?;
+                    tab.set("control", cc.control)?;
+                    tab.set("value", cc.value)?;
+                },
+                MidiMessage::ProgramChange(channel, prgm) => {
+                    tab.set("event", "program_change")?;
+                    tab.set("channel", util::midi_channel_to_num(channel))?;
+                    tab.set("program", *prgm)?;
+                },
+                MidiMessage::PitchBend(channel, lsb, msb) => {
+                    tab.set("channel", util::midi_channel_to_num(channel))?;
+                    let true_val: u16 = ((*msb as u16) << 8) | *lsb as u16;
+                    tab.set("event", "pitch_bend")?;
+                    tab.set("value", true_val)?;
+                },
+                x => {
+                    debug!("Unknown MIDI message seen: {:?}", x);
+                    return Ok(());
+                },
+            }
+
+            on_midi_recv.call::<_, ()>((tab,))?;
+        },
+        Message::Evdev(device, ev, received) => {
+            // Only the scripts that opened the device see its events
+            if !api::evdev::opened_by(lua, *device) {
+                return Ok(());
+            }
+            api::map::handle_evdev(lua, *device, ev)?;
+
+            let on_input_event = match lua.globals().get::<&str, mlua::Function>("on_input_event") {
+                Ok(x) => x,
+                Err(_) => return Ok(()),
+            };
+
+            let tab = lua.create_table()?;
+            tab.set("device", *device)?;
+            tab.set("code", ev.code)?;
+            tab.set("value", ev.value)?;
+            tab.set("received_ns", api::misc::nanos_since_start(*received))?;
+            match ev.kind {
+                input_linux::EventKind::Key => {
+                    tab.set("event", "key")?;
+                    tab.set("pressed", ev.value != 0)?;
+                },
+                input_linux::EventKind::Absolute => {
+                    tab.set("event", "axis")?;
+                    tab.set("normalised", api::evdev::normalise(*device, ev.code, ev.value))?;
+                },
+                _ => return Ok(()),
+            }
+
+            on_input_event.call::<_, ()>((tab,))?;
+        },
+        Message::Clock(event, time) => api::clock::handle(lua, *event, time)?,
+        Message::Osc(msg, received) => {
+            let on_osc_recv = match lua.globals().get::<&str, mlua::Function>("on_osc_recv") {
+                Ok(x) => x,
+                Err(_) => return Ok(()),
+            };
+
+            let args = lua.create_table()?;
+            for (i, arg) in msg.args.iter().enumerate() {
+                args.set(i + 1, api::osc::arg_to_lua(lua, arg)?)?;
+            }
+            on_osc_recv.call::<_, ()>((msg.addr.as_str(), args, api::misc::nanos_since_start(*received)))?;
+        },
+        Message::Focus { app_id, title } => {
+            api::focus::switch(lua, app_id, title);
+
+            let on_focus_change = match lua.globals().get::<&str, mlua::Function>("on_focus_change") {
+                Ok(x) => x,
+                Err(_) => return Ok(()),
+            };
+            on_focus_change.call::<_, ()>((app_id.as_str(), title.as_str()))?;
+        },
+        Message::Bus { from, topic, data } => {
+            // Scripts don't hear what they published themselves
+            if *from == script::id(lua) {
+                return Ok(());
+            }
+            let on_bus_recv = match lua.globals().get::<&str, mlua::Function>("on_bus_recv") {
+                Ok(x) => x,
+                Err(_) => return Ok(()),
+            };
+
+            on_bus_recv.call::<_, ()>((topic.as_str(), lua.to_value(data)?, script::name(*from)))?;
+        },
+        Message::Device { added, device } => {
+            let name = if *added { "on_device_added" } else { "on_device_removed" };
+            let handler = match lua.globals().get::<&str, mlua::Function>(name) {
+                Ok(x) => x,
+                Err(_) => return Ok(()),
+            };
+            handler.call::<_, ()>(device.to_table(lua)?)?;
+        },
+        Message::Ipc(_) | Message::Quit => {},
+        #[cfg(feature = "web")]
+        Message::Web(..) => {},
     }
-    info!("Running script {:?}", script_path);
 
-    let uinput_fd = {
-        let uinput_path = Path::new("/dev").join("uinput");
-        if !uinput_path.exists() {
-            fatal_error!("Could not find /dev/uinput. Is uinput installed?");
+    Ok(())
+}
+
+/// Turns the first SIGINT or SIGTERM into a `Quit`, so scripts get to stop
+/// and take their devices with them. A second one exits straight away, in
+/// case a script is stuck and the dispatcher never gets to the first.
+fn handle_signals(sender: MessageSender) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        let mut asked = false;
+        loop {
+            tokio::select! {
+                _ = interrupt.recv() => {},
+                _ = terminate.recv() => {},
+            }
+            if asked {
+                std::process::exit(130);
+            }
+            info!("Stopping");
+            asked = true;
+            let _ = sender.send(Message::Quit);
         }
-        let a = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .custom_flags(libc::O_NONBLOCK)
-            .open(uinput_path)?;
-        a
+    });
+
+    Ok(())
+}
+
+/// Runs the dispatcher until it's told to quit. This is the only place the
+/// scripts' Lua states are touched, so nothing else needs to lock them.
+async fn run_dispatcher(mut scripts: Scripts, run: RunArgs, sender: MessageSender, mut events: MessageReceiver) -> anyhow::Result<()> {
+    // Kept until the dispatcher stops, which removes the socket
+    let _socket = match &run.socket {
+        Some(socket) => {
+            let path = socket.clone().unwrap_or_else(ipc::default_path);
+            Some(ipc::serve(&path, sender.clone())?)
+        },
+        None => None,
     };
-    let uinput = input_linux::UInputHandle::new(uinput_fd);
-    debug!("uinput opened");
+    if let Some(addr) = run.http {
+        #[cfg(feature = "web")]
+        web::serve(addr, run.web_root.clone(), sender.clone())?;
+        #[cfg(not(feature = "web"))]
+        anyhow::bail!("Can't serve on {}, handcake was built without the web feature", addr);
+    }
 
-    let script_text = std::fs::read_to_string(&script_path)?;
-    let lua = mlua::Lua::new();
-    let a = lua.load(&script_text);
-    let a = a.set_name(&script_path.to_string_lossy().as_bytes())?;
+    // Replays shouldn't depend on what's on screen or plugged in
+    if !api::midi::OFFLINE.load(std::sync::atomic::Ordering::Relaxed) {
+        if !focus::watch(sender.clone()) {
+            debug!("No window system to watch for focus changes");
+        }
+        hotplug::watch(sender.clone());
+    }
+    handle_signals(sender)?;
 
-    api::midi::Midi::register_api(&lua, ()).unwrap();
-    api::gamepad::Gamepad::register_api(&lua, (uinput,)).unwrap();
-    api::misc::Misc::register_api(&lua, ()).unwrap();
+    // The loop below runs on this thread
+    if run.realtime {
+        stats::go_realtime();
+    }
+    if run.stats {
+        stats::enable();
+    }
 
-    debug!("Evaluating initial script");
+    debug!("Receiving messages");
+
+    // Replays send their own ticks, along with moving simulated time on
+    let simulated = api::misc::simulated();
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+    // A slow script shouldn't be followed by a burst of catch-up ticks
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut report = tokio::time::interval_at(tokio::time::Instant::now() + stats::REPORT_INTERVAL, stats::REPORT_INTERVAL);
 
-    a.exec()?;
-    debug!("Calling on_script_init()");
+    loop {
+        let msg = tokio::select! {
+            msg = events.recv() => match msg {
+                Some(x) => x,
+                None => break,
+            },
+            _ = tick.tick(), if !simulated => Message::Tick,
+            _ = report.tick(), if run.stats => {
+                stats::report();
+                continue;
+            },
+        };
 
-    {
-        let globals = &lua.globals();
-        let on_script_init = globals.get::<&str, mlua::Function>("on_script_init")?;
-        on_script_init.call::<(), ()>(())?;
+        match &msg {
+            Message::Midi(_, time) => stats::begin(time.received),
+            Message::Tick => api::misc::advance(TICK_INTERVAL),
+            _ => {},
+        }
+        let running = scripts.handle(msg);
+        stats::end();
+        if !running {
+            break;
+        }
     }
 
-    let lua = Arc::new(Mutex::new(lua));
+    if run.stats {
+        stats::report_all();
+    }
 
-    debug!("Receiving messages");
+    Ok(())
+}
 
-    let mut threads = vec![];
-    
-    threads.push(std::thread::spawn(move || {
-        let (_, recv) = MESSAGE.clone();
-
-        let lock = recv.lock();
-        while let Ok(x) = lock.recv() {
-            #[allow(irrefutable_let_patterns)]
-            if let Message::Midi(midi) = x {
-                let lua = lua.lock();
-                let on_midi_recv = lua.globals().get::<&str, mlua::Function>("on_midi_recv");
-                if on_midi_recv.is_err() {
-                    continue;
-                }
-                let on_midi_recv = on_midi_recv.unwrap();
-                if let MidiMessage::Invalid = midi {
-                    continue;
-                }
-
-                let tab = lua.create_table().unwrap();
-
-                match &midi {
-                    MidiMessage::NoteOn(channel, key) => {
-                        tab.set("event", "note_on").unwrap();
-                        tab.set("channel", util::midi_channel_to_num(channel)).unwrap();
-                        tab.set("key", key.key).unwrap();
-                        tab.set("vel", key.value).unwrap();
-                        tab.set("is_note", true).unwrap();
-                    },
-                    MidiMessage::NoteOff(channel, key) => {
-                        tab.set("event", "note_off").unwrap();
-                        tab.set("channel", util::midi_channel_to_num(channel)).unwrap();
-                        tab.set("key", key.key).unwrap();
-                        tab.set("vel", key.value).unwrap();
-                        tab.set("is_note", true).unwrap();
-                    },
-                    MidiMessage::ControlChange(channel, cc) => {
-                        tab.set("event", "control_change").unwrap();
-                        tab.set("channel", util::midi_channel_to_num(channel)).unwrap();
-                        tab.set("control", cc.control).unwrap();
-                        tab.set("value", cc.value).unwrap();
-                    },
-                    MidiMessage::ProgramChange(channel, prgm) => {
-                        tab.set("event", "program_change").unwrap();
-                        tab.set("channel", util::midi_channel_to_num(channel)).unwrap();
-                        tab.set("program", *prgm).unwrap();
-                    },
-                    MidiMessage::PitchBend(channel, lsb, msb) => {
-                        tab.set("channel", util::midi_channel_to_num(channel)).unwrap();
-                        let true_val: u16 = ((*msb as u16) << 8) | *lsb as u16;
-                        tab.set("event", "pitch_bend").unwrap();
-                        tab.set("value", true_val).unwrap();
-                    },
-                    x => {
-                        debug!("Unknown MIDI message seen: {:?}", x);
-                        continue;
-                    },
-                }
-
-                on_midi_recv.call::<_, ()>((tab,)).unwrap();
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    if cfg!(debug_assertions) {
+        pretty_env_logger::formatted_builder().filter_level(log::LevelFilter::Debug).init();
+    } else {
+        pretty_env_logger::init();
+    }
+    // Receive times are counted from here, so it can't wait for first use
+    lazy_static::initialize(&api::misc::START_TIME);
+
+    let cli = HandcakeApplication::parse();
+    info!("handcake v{} starting - (c)2022 rin", env!("CARGO_PKG_VERSION"));
+
+    match cli.command {
+        None => {
+            let (sender, events) = tokio::sync::mpsc::unbounded_channel();
+            let scripts = Scripts::start(&cli.run, output_backend(&cli.run), sender.clone())?;
+            run_dispatcher(scripts, cli.run, sender, events).await?;
+        },
+        Some(Command::Record { out, port }) => {
+            session::record(port, &out).await?;
+        },
+        Some(Command::Replay { log, run, speed }) => {
+            if !(speed > 0.0 && speed.is_finite()) {
+                fatal_error!("Replay speed must be a positive number");
             }
-        }
-    }));
+            let events = match session::read_log(&log) {
+                Ok(x) => x,
+                Err(e) => {
+                    fatal_error!("Could not read session log: {}", e);
+                },
+            };
+            info!("Replaying {} events from {:?}", events.len(), log);
+
+            api::midi::OFFLINE.store(true, std::sync::atomic::Ordering::Relaxed);
+            api::misc::simulate();
+            let (sender, messages) = tokio::sync::mpsc::unbounded_channel();
+            let scripts = Scripts::start(&run, output_backend(&run), sender.clone())?;
 
-    for i in threads {
-        i.join().unwrap();
+            {
+                let sender = sender.clone();
+                std::thread::spawn(move || session::replay(events, speed, sender));
+            }
+            run_dispatcher(scripts, run, sender, messages).await?;
+        },
+        Some(Command::Learn { out, port, force, controls }) => {
+            learn::run(port, &out, &controls, force)?;
+        },
+        Some(Command::Test { files, script, config }) => {
+            api::midi::OFFLINE.store(true, std::sync::atomic::Ordering::Relaxed);
+            let run = RunArgs { script: script.into_iter().collect(), config, dry_run: true, ..Default::default() };
+            if !testing::run(&files, &run) {
+                std::process::exit(1);
+            }
+        },
     }
 
     Ok(())
@@ -0,0 +1,274 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+use mlua::{Lua, RegistryKey};
+
+pub enum ModifierKind {
+    /// Repeatedly presses the button while held, `rate` times a second.
+    Turbo { rate: f32, duty: f32 },
+    /// Each press flips the button between held and released.
+    Toggle,
+    /// A press keeps the button held for at least `duration`.
+    Hold { duration: Duration },
+    /// A second press within `window` goes to `button` instead, and calls `callback`.
+    DoubleTap { window: Duration, button: i32, callback: Option<Arc<RegistryKey>> },
+}
+
+pub enum Effect {
+    Set(i32, bool),
+    Call(Arc<RegistryKey>, i32),
+}
+
+/// A time in seconds from a modifier's table. Negative ones count as none.
+fn seconds(tab: &mlua::Table, key: &str, default: f32) -> mlua::Result<Duration> {
+    let secs = tab.get::<_, Option<f32>>(key)?.unwrap_or(default);
+    let bad = || mlua::Error::RuntimeError(format!("{} must be a number of seconds, not {}", key, secs));
+    if !secs.is_finite() {
+        return Err(bad());
+    }
+    Duration::try_from_secs_f32(secs.max(0.0)).map_err(|_| bad())
+}
+
+pub struct Modifier {
+    kind: ModifierKind,
+    held: bool,
+    output: bool,
+    since: Instant,
+    last_press: Option<Instant>,
+    redirected: bool,
+}
+
+impl Modifier {
+    pub fn new(kind: ModifierKind) -> Self {
+        Self {
+            kind,
+            held: false,
+            output: false,
+            since: crate::api::misc::now(),
+            last_press: None,
+            redirected: false,
+        }
+    }
+
+    pub fn from_table(l: &Lua, button: i32, tab: mlua::Table) -> mlua::Result<Self> {
+        let mode = tab.get::<_, String>("mode")?;
+        let kind = match mode.as_str() {
+            "turbo" => {
+                let rate = tab.get::<_, Option<f32>>("rate")?.unwrap_or(10.0);
+                if !(rate > 0.0 && rate.is_finite()) {
+                    return Err(mlua::Error::RuntimeError("turbo rate must be positive".into()));
+                }
+                let duty = tab.get::<_, Option<f32>>("duty")?.unwrap_or(0.5);
+                if duty.is_nan() {
+                    return Err(mlua::Error::RuntimeError("turbo duty can't be NaN".into()));
+                }
+                ModifierKind::Turbo {
+                    rate,
+                    duty: duty.clamp(0.0, 1.0),
+                }
+            },
+            "toggle" => ModifierKind::Toggle,
+            "hold" => ModifierKind::Hold {
+                duration: seconds(&tab, "duration", 0.5)?,
+            },
+            "double_tap" => ModifierKind::DoubleTap {
+                window: seconds(&tab, "window", 0.3)?,
+                button: tab.get::<_, Option<i32>>("button")?.unwrap_or(button),
+                callback: match tab.get::<_, Option<mlua::Function>>("callback")? {
+                    Some(f) => Some(Arc::new(l.create_registry_value(f)?)),
+                    None => None,
+                },
+            },
+            x => return Err(mlua::Error::RuntimeError(format!("unknown button modifier '{}'", x))),
+        };
+
+        Ok(Self::new(kind))
+    }
+
+    fn set(&mut self, code: i32, state: bool, fx: &mut Vec<Effect>) {
+        if self.output != state {
+            self.output = state;
+            fx.push(Effect::Set(code, state));
+        }
+    }
+
+    pub fn press(&mut self, code: i32, pressed: bool, now: Instant) -> Vec<Effect> {
+        let mut fx = vec![];
+        let was_held = self.held;
+        self.held = pressed;
+
+        match &self.kind {
+            ModifierKind::Turbo { .. } => {
+                if pressed && !was_held {
+                    self.since = now;
+                }
+                self.set(code, pressed, &mut fx);
+            },
+            ModifierKind::Toggle => {
+                if pressed && !was_held {
+                    let out = !self.output;
+                    self.set(code, out, &mut fx);
+                }
+            },
+            ModifierKind::Hold { duration } => {
+                if pressed {
+                    if !was_held {
+                        self.since = now;
+                    }
+                    self.set(code, true, &mut fx);
+                } else if now.duration_since(self.since) >= *duration {
+                    self.set(code, false, &mut fx);
+                }
+            },
+            ModifierKind::DoubleTap { window, button, callback } => {
+                let (window, button, callback) = (*window, *button, callback.clone());
+                if pressed && !was_held {
+                    let double = matches!(self.last_press, Some(t) if now.duration_since(t) <= window);
+                    if double {
+                        self.last_press = None;
+                        self.redirected = true;
+                        fx.push(Effect::Set(button, true));
+                        if let Some(f) = callback {
+                            fx.push(Effect::Call(f, code));
+                        }
+                    } else {
+                        self.last_press = Some(now);
+                        self.set(code, true, &mut fx);
+                    }
+                } else if !pressed {
+                    if self.redirected {
+                        self.redirected = false;
+                        fx.push(Effect::Set(button, false));
+                    } else {
+                        self.set(code, false, &mut fx);
+                    }
+                }
+            },
+        }
+
+        fx
+    }
+
+    pub fn tick(&mut self, code: i32, now: Instant) -> Vec<Effect> {
+        let mut fx = vec![];
+
+        match &self.kind {
+            ModifierKind::Turbo { rate, duty } => {
+                if self.held {
+                    let period = 1.0 / rate;
+                    let phase = (now.duration_since(self.since).as_secs_f32() % period) / period;
+                    let want = phase < *duty;
+                    self.set(code, want, &mut fx);
+                }
+            },
+            ModifierKind::Hold { duration } => {
+                if !self.held && now.duration_since(self.since) >= *duration {
+                    self.set(code, false, &mut fx);
+                }
+            },
+            ModifierKind::Toggle | ModifierKind::DoubleTap { .. } => {},
+        }
+
+        fx
+    }
+
+    /// Whatever needs undoing when the modifier is removed.
+    pub fn release(&mut self, code: i32) -> Vec<Effect> {
+        let mut fx = vec![];
+        if self.redirected {
+            if let ModifierKind::DoubleTap { button, .. } = &self.kind {
+                fx.push(Effect::Set(*button, false));
+            }
+        }
+        self.set(code, false, &mut fx);
+        fx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(x: u64) -> Duration {
+        Duration::from_millis(x)
+    }
+
+    /// The buttons set, leaving out callbacks.
+    fn sets(fx: Vec<Effect>) -> Vec<(i32, bool)> {
+        fx.into_iter().filter_map(|x| match x {
+            Effect::Set(code, state) => Some((code, state)),
+            Effect::Call(..) => None,
+        }).collect()
+    }
+
+    #[test]
+    fn turbo() {
+        let t = Instant::now();
+        let mut m = Modifier::new(ModifierKind::Turbo { rate: 10.0, duty: 0.5 });
+        assert_eq!(sets(m.press(1, true, t)), [(1, true)]);
+        assert_eq!(sets(m.tick(1, t + ms(20))), []);
+        assert_eq!(sets(m.tick(1, t + ms(60))), [(1, false)]);
+        assert_eq!(sets(m.tick(1, t + ms(110))), [(1, true)]);
+        assert_eq!(sets(m.press(1, false, t + ms(120))), [(1, false)]);
+        assert_eq!(sets(m.tick(1, t + ms(210))), []);
+    }
+
+    #[test]
+    fn toggle() {
+        let t = Instant::now();
+        let mut m = Modifier::new(ModifierKind::Toggle);
+        assert_eq!(sets(m.press(1, true, t)), [(1, true)]);
+        assert_eq!(sets(m.press(1, false, t)), []);
+        assert_eq!(sets(m.press(1, true, t)), [(1, false)]);
+        assert_eq!(sets(m.press(1, false, t)), []);
+    }
+
+    #[test]
+    fn hold() {
+        let t = Instant::now();
+        let mut m = Modifier::new(ModifierKind::Hold { duration: ms(500) });
+        assert_eq!(sets(m.press(1, true, t)), [(1, true)]);
+        assert_eq!(sets(m.press(1, false, t + ms(100))), []);
+        assert_eq!(sets(m.tick(1, t + ms(400))), []);
+        assert_eq!(sets(m.tick(1, t + ms(500))), [(1, false)]);
+
+        // Held for longer than the duration, it goes as soon as it's let go
+        assert_eq!(sets(m.press(1, true, t + ms(1000))), [(1, true)]);
+        assert_eq!(sets(m.press(1, false, t + ms(2000))), [(1, false)]);
+    }
+
+    #[test]
+    fn double_tap() {
+        let t = Instant::now();
+        let mut m = Modifier::new(ModifierKind::DoubleTap { window: ms(300), button: 2, callback: None });
+        assert_eq!(sets(m.press(1, true, t)), [(1, true)]);
+        assert_eq!(sets(m.press(1, false, t + ms(50))), [(1, false)]);
+        assert_eq!(sets(m.press(1, true, t + ms(200))), [(2, true)]);
+        assert_eq!(sets(m.release(1)), [(2, false)]);
+
+        // Too slow for a double tap
+        let mut m = Modifier::new(ModifierKind::DoubleTap { window: ms(300), button: 2, callback: None });
+        m.press(1, true, t);
+        m.press(1, false, t + ms(50));
+        assert_eq!(sets(m.press(1, true, t + ms(400))), [(1, true)]);
+        assert_eq!(sets(m.press(1, false, t + ms(450))), [(1, false)]);
+    }
+
+    #[test]
+    fn bad_tables() {
+        let l = Lua::new();
+        for code in [
+            "{ mode = 'turbo', rate = 0/0 }",
+            "{ mode = 'turbo', rate = math.huge }",
+            "{ mode = 'turbo', rate = -1 }",
+            "{ mode = 'hold', duration = math.huge }",
+            "{ mode = 'hold', duration = 1e30 }",
+            "{ mode = 'double_tap', window = 0/0 }",
+            "{ mode = 'mash' }",
+        ] {
+            let tab = l.load(code).eval::<mlua::Table>().unwrap();
+            assert!(Modifier::from_table(&l, 1, tab).is_err(), "{}", code);
+        }
+
+        let tab = l.load("{ mode = 'hold', duration = -1 }").eval::<mlua::Table>().unwrap();
+        assert!(Modifier::from_table(&l, 1, tab).is_ok());
+    }
+}
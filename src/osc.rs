@@ -0,0 +1,257 @@
+// Just enough of OSC 1.0 to talk to control surfaces like TouchOSC:
+// messages and bundles with the common argument types.
+
+/// How deep bundles can be nested in a packet before it's refused.
+const MAX_BUNDLE_DEPTH: usize = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Str(String),
+    Blob(Vec<u8>),
+    Bool(bool),
+    Nil,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub addr: String,
+    pub args: Vec<OscArg>,
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+    pad(buf);
+}
+
+pub fn encode(msg: &OscMessage) -> Vec<u8> {
+    let mut buf = vec![];
+    write_str(&mut buf, &msg.addr);
+
+    let mut tags = String::from(",");
+    for arg in &msg.args {
+        tags.push(match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Long(_) => 'h',
+            OscArg::Float(_) => 'f',
+            OscArg::Double(_) => 'd',
+            OscArg::Str(_) => 's',
+            OscArg::Blob(_) => 'b',
+            OscArg::Bool(true) => 'T',
+            OscArg::Bool(false) => 'F',
+            OscArg::Nil => 'N',
+        });
+    }
+    write_str(&mut buf, &tags);
+
+    for arg in &msg.args {
+        match arg {
+            OscArg::Int(x) => buf.extend_from_slice(&x.to_be_bytes()),
+            OscArg::Long(x) => buf.extend_from_slice(&x.to_be_bytes()),
+            OscArg::Float(x) => buf.extend_from_slice(&x.to_be_bytes()),
+            OscArg::Double(x) => buf.extend_from_slice(&x.to_be_bytes()),
+            OscArg::Str(x) => write_str(&mut buf, x),
+            OscArg::Blob(x) => {
+                buf.extend_from_slice(&(x.len() as i32).to_be_bytes());
+                buf.extend_from_slice(x);
+                pad(&mut buf);
+            },
+            OscArg::Bool(_) | OscArg::Nil => {},
+        }
+    }
+
+    buf
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(n).filter(|x| *x <= self.data.len()).ok_or("packet is truncated")?;
+        let out = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn align(&mut self) {
+        self.pos = (self.pos + 3) & !3;
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let rest = &self.data[self.pos.min(self.data.len())..];
+        let len = rest.iter().position(|x| *x == 0).ok_or("string is not terminated")?;
+        let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos += len + 1;
+        self.align();
+        Ok(s)
+    }
+}
+
+fn decode_message(data: &[u8]) -> Result<OscMessage, String> {
+    let mut r = Reader { data, pos: 0 };
+    let addr = r.string()?;
+    if !addr.starts_with('/') {
+        return Err(format!("bad address '{}'", addr));
+    }
+
+    // Some old senders leave the type tags off entirely
+    if r.pos >= data.len() {
+        return Ok(OscMessage { addr, args: vec![] });
+    }
+    let tags = r.string()?;
+    let tags = tags.strip_prefix(',').ok_or("type tags don't start with ','")?;
+
+    let mut args = vec![];
+    for tag in tags.chars() {
+        args.push(match tag {
+            'i' => OscArg::Int(i32::from_be_bytes(r.take_array()?)),
+            'h' => OscArg::Long(i64::from_be_bytes(r.take_array()?)),
+            'f' => OscArg::Float(f32::from_be_bytes(r.take_array()?)),
+            'd' => OscArg::Double(f64::from_be_bytes(r.take_array()?)),
+            's' | 'S' => OscArg::Str(r.string()?),
+            'b' => {
+                let len = i32::from_be_bytes(r.take_array()?);
+                let len = usize::try_from(len).map_err(|_| "negative blob length")?;
+                let blob = r.take(len)?.to_vec();
+                r.align();
+                OscArg::Blob(blob)
+            },
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            'N' | 'I' => OscArg::Nil,
+            x => return Err(format!("unsupported argument type '{}'", x)),
+        });
+    }
+
+    Ok(OscMessage { addr, args })
+}
+
+/// Decodes a packet into its messages. Bundles are flattened and their
+/// timetags ignored, everything is handled as soon as it arrives.
+pub fn decode(data: &[u8]) -> Result<Vec<OscMessage>, String> {
+    decode_nested(data, 0)
+}
+
+fn decode_nested(data: &[u8], depth: usize) -> Result<Vec<OscMessage>, String> {
+    if !data.starts_with(b"#bundle\0") {
+        return Ok(vec![decode_message(data)?]);
+    }
+    if depth >= MAX_BUNDLE_DEPTH {
+        return Err(format!("bundles are nested more than {} deep", MAX_BUNDLE_DEPTH));
+    }
+
+    let mut r = Reader { data, pos: 16 };
+    let mut out = vec![];
+    while r.pos < data.len() {
+        let len = i32::from_be_bytes(r.take_array()?);
+        let len = usize::try_from(len).map_err(|_| "negative bundle element length")?;
+        out.extend(decode_nested(r.take(len)?, depth + 1)?);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(args: Vec<OscArg>) -> OscMessage {
+        OscMessage { addr: "/test".into(), args }
+    }
+
+    fn round_trip(msg: &OscMessage) {
+        let data = encode(msg);
+        assert_eq!(data.len() % 4, 0, "{:?} isn't padded", data);
+        assert_eq!(decode(&data).unwrap(), vec![msg.clone()]);
+    }
+
+    /// Wraps packets up in a bundle, with an immediate timetag.
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = b"#bundle\0".to_vec();
+        buf.extend_from_slice(&1u64.to_be_bytes());
+        for x in elements {
+            buf.extend_from_slice(&(x.len() as i32).to_be_bytes());
+            buf.extend_from_slice(x);
+        }
+        buf
+    }
+
+    #[test]
+    fn every_argument_type() {
+        round_trip(&message(vec![OscArg::Int(-7)]));
+        round_trip(&message(vec![OscArg::Long(1 << 40)]));
+        round_trip(&message(vec![OscArg::Float(0.25)]));
+        round_trip(&message(vec![OscArg::Double(-1.5e100)]));
+        round_trip(&message(vec![OscArg::Str("fader".into())]));
+        round_trip(&message(vec![OscArg::Blob(vec![1, 2, 3, 4, 5])]));
+        round_trip(&message(vec![OscArg::Bool(true), OscArg::Bool(false), OscArg::Nil]));
+        round_trip(&message(vec![]));
+    }
+
+    #[test]
+    fn padding() {
+        // Strings take their terminator and then pad to 4 bytes, so a string
+        // of 3 needs no padding and a string of 4 needs a whole extra word
+        for len in 0..9 {
+            let s = "x".repeat(len);
+            round_trip(&OscMessage { addr: format!("/{}", s), args: vec![OscArg::Str(s.clone()), OscArg::Int(1)] });
+            round_trip(&message(vec![OscArg::Blob(vec![9; len]), OscArg::Int(1)]));
+        }
+
+        assert_eq!(encode(&message(vec![OscArg::Str("abc".into())])), b"/test\0\0\0,s\0\0abc\0");
+    }
+
+    #[test]
+    fn bundles_are_flattened() {
+        let a = message(vec![OscArg::Int(1)]);
+        let b = message(vec![OscArg::Float(2.0)]);
+        let c = message(vec![OscArg::Str("three".into())]);
+        let data = bundle(&[encode(&a), bundle(&[encode(&b), encode(&c)])]);
+        assert_eq!(decode(&data).unwrap(), vec![a, b, c]);
+    }
+
+    #[test]
+    fn deep_bundles_are_refused() {
+        let mut data = encode(&message(vec![]));
+        for _ in 0..MAX_BUNDLE_DEPTH {
+            data = bundle(&[data]);
+        }
+        assert!(decode(&data).is_ok());
+        assert!(decode(&bundle(&[data])).is_err());
+    }
+
+    #[test]
+    fn over_loopback() {
+        let rx = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let msg = message(vec![OscArg::Float(0.5), OscArg::Str("hi".into())]);
+        tx.send_to(&encode(&msg), rx.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = rx.recv(&mut buf).unwrap();
+        assert_eq!(decode(&buf[..n]).unwrap(), vec![msg]);
+    }
+
+    #[test]
+    fn truncated_packets_are_refused() {
+        let data = encode(&message(vec![OscArg::Double(1.0)]));
+        assert!(decode(&data[..data.len() - 1]).is_err());
+    }
+}
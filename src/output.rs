@@ -0,0 +1,153 @@
+use std::{fs::File, io, collections::{HashMap, VecDeque}, sync::Arc};
+use input_linux::{
+    UInputHandle,
+    EventKind,
+    Key,
+    AbsoluteAxis,
+    InputId,
+    AbsoluteInfoSetup,
+    InputEvent,
+    sys::input_event,
+};
+use parking_lot::Mutex;
+
+/// A virtual input device, set up the same way as a uinput one.
+pub trait OutputDevice: Send {
+    fn set_evbit(&self, kind: EventKind) -> io::Result<()>;
+    fn set_keybit(&self, key: Key) -> io::Result<()>;
+    fn set_absbit(&self, axis: AbsoluteAxis) -> io::Result<()>;
+    fn create(&self, id: &InputId, name: &[u8], abs: &[AbsoluteInfoSetup]) -> io::Result<()>;
+    fn write(&self, events: &[input_event]) -> io::Result<()>;
+}
+
+/// Somewhere to create virtual devices.
+pub trait OutputBackend: Send + Sync {
+    fn open(&self) -> anyhow::Result<Box<dyn OutputDevice>>;
+}
+
+impl OutputDevice for UInputHandle<File> {
+    fn set_evbit(&self, kind: EventKind) -> io::Result<()> {
+        UInputHandle::set_evbit(self, kind)
+    }
+
+    fn set_keybit(&self, key: Key) -> io::Result<()> {
+        UInputHandle::set_keybit(self, key)
+    }
+
+    fn set_absbit(&self, axis: AbsoluteAxis) -> io::Result<()> {
+        UInputHandle::set_absbit(self, axis)
+    }
+
+    fn create(&self, id: &InputId, name: &[u8], abs: &[AbsoluteInfoSetup]) -> io::Result<()> {
+        UInputHandle::create(self, id, name, 0, abs)
+    }
+
+    fn write(&self, events: &[input_event]) -> io::Result<()> {
+        UInputHandle::write(self, events)?;
+        crate::stats::written();
+        Ok(())
+    }
+}
+
+pub struct UInputBackend;
+impl OutputBackend for UInputBackend {
+    fn open(&self) -> anyhow::Result<Box<dyn OutputDevice>> {
+        Ok(Box::new(crate::util::open_uinput()?))
+    }
+}
+
+/// How many events a recording device keeps, so a long dry run doesn't
+/// keep growing.
+const MAX_RECORDED: usize = 10_000;
+
+/// Everything a recording device has been sent, or the latest of it.
+#[derive(Default, Debug)]
+pub struct Recorded {
+    pub name: String,
+    pub events: VecDeque<InputEvent>,
+    pub keys: HashMap<u16, i32>,
+    pub abs: HashMap<u16, i32>,
+}
+
+pub struct RecordingDevice {
+    recorded: Arc<Mutex<Recorded>>,
+    log: bool,
+}
+
+impl OutputDevice for RecordingDevice {
+    fn set_evbit(&self, _kind: EventKind) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_keybit(&self, _key: Key) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_absbit(&self, _axis: AbsoluteAxis) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn create(&self, _id: &InputId, name: &[u8], abs: &[AbsoluteInfoSetup]) -> io::Result<()> {
+        let mut rec = self.recorded.lock();
+        rec.name = String::from_utf8_lossy(name).into_owned();
+        for a in abs {
+            rec.abs.insert(a.axis as u16, a.info.value);
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, events: &[input_event]) -> io::Result<()> {
+        let mut rec = self.recorded.lock();
+        for ev in events {
+            let ev = *InputEvent::from_raw(ev).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            match ev.kind {
+                EventKind::Key => {
+                    rec.keys.insert(ev.code, ev.value);
+                },
+                EventKind::Absolute => {
+                    rec.abs.insert(ev.code, ev.value);
+                },
+                _ => {},
+            }
+            if self.log && ev.kind != EventKind::Synchronize {
+                info!("[dry run] {}: {:?} {} = {}", rec.name, ev.kind, ev.code, ev.value);
+            }
+            if rec.events.len() == MAX_RECORDED {
+                rec.events.pop_front();
+            }
+            rec.events.push_back(ev);
+        }
+        crate::stats::written();
+
+        Ok(())
+    }
+}
+
+/// Keeps everything in memory instead of touching /dev/uinput, so this can
+/// run without root and output can be inspected afterwards.
+pub struct RecordingBackend {
+    pub devices: Mutex<Vec<Arc<Mutex<Recorded>>>>,
+    log: bool,
+}
+
+impl RecordingBackend {
+    pub fn new(log: bool) -> Self {
+        Self {
+            devices: Mutex::new(vec![]),
+            log,
+        }
+    }
+}
+
+impl OutputBackend for RecordingBackend {
+    fn open(&self) -> anyhow::Result<Box<dyn OutputDevice>> {
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+        self.devices.lock().push(recorded.clone());
+
+        Ok(Box::new(RecordingDevice {
+            recorded,
+            log: self.log,
+        }))
+    }
+}
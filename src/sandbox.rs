@@ -0,0 +1,197 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use mlua::HookTriggers;
+
+use crate::RunArgs;
+
+/// How many instructions run between checks on the instruction budget.
+const CHECK_EVERY: u32 = 10_000;
+
+const DEFAULT_INSTRUCTIONS: u64 = 10_000_000;
+const DEFAULT_MEMORY_MB: usize = 64;
+
+// Takes away everything that reaches outside of handcake's own devices:
+// files, other processes, the environment and native code. Binary chunks
+// go too, since broken bytecode can do anything, and so does sleeping,
+// which the instruction budget can't see. Modules are still found, but
+// only as source and only on the package.path the script started with,
+// which is passed in. Reading or making keyboards, and OSC to or from
+// other machines, are refused by the APIs themselves, see `sandboxed`.
+const RESTRICT: &str = r#"
+local path = ...
+local open, load, searchpath = io.open, load, package.searchpath
+
+io = nil
+debug = nil
+dofile = nil
+loadfile = nil
+for _, name in ipairs({"execute", "exit", "getenv", "remove", "rename", "tmpname", "setlocale"}) do
+    os[name] = nil
+end
+macro.save = nil
+macro.load = nil
+misc.sleep = nil
+
+-- An env that's left out has to stay out, since a nil one means no globals
+function _G.load(chunk, name, _, ...)
+    if select('#', ...) > 0 then
+        return load(chunk, name, "t", ...)
+    end
+    return load(chunk, name, "t")
+end
+
+package.loadlib = nil
+package.cpath = ""
+package.searchers = {
+    package.searchers[1],
+    function(name)
+        local found, err = searchpath(name, path)
+        if not found then
+            return err
+        end
+        local file = assert(open(found, "rb"))
+        local source = file:read("a")
+        file:close()
+        return assert(load(source, "@" .. found, "t")), found
+    end,
+}
+"#;
+
+/// What scripts are allowed to do.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Limits {
+    pub sandbox: bool,
+    /// Roughly how many Lua instructions a script gets per message.
+    pub instructions: Option<u64>,
+    /// In bytes.
+    pub memory: Option<usize>,
+}
+
+impl Limits {
+    /// Sandboxed scripts get some limits even when none are given.
+    pub fn from_args(run: &RunArgs) -> Self {
+        let (instructions, memory) = match run.sandbox {
+            true => (Some(DEFAULT_INSTRUCTIONS), Some(DEFAULT_MEMORY_MB)),
+            false => (None, None),
+        };
+
+        Self {
+            sandbox: run.sandbox,
+            instructions: run.max_instructions.or(instructions),
+            memory: run.max_memory.or(memory).map(|x| x * 1024 * 1024),
+        }
+    }
+}
+
+/// Marks a sandboxed Lua state.
+struct Sandboxed;
+
+/// Whether the script runs in the sandbox, for APIs that only take away
+/// part of what they do.
+pub fn sandboxed(l: &mlua::Lua) -> bool {
+    l.app_data_ref::<Sandboxed>().is_some()
+}
+
+/// Instructions used since the last refill, kept in the Lua state.
+struct Budget {
+    max: u64,
+    used: AtomicU64,
+}
+
+/// Sets up a fresh Lua state with the limits. Has to be called after the
+/// APIs are registered, since the sandbox takes some of them away again.
+pub fn apply(l: &mlua::Lua, limits: &Limits) -> mlua::Result<()> {
+    if limits.sandbox {
+        l.set_app_data(Sandboxed);
+        // Copied out now, since the script can set package.path to anything
+        let path = l.globals().get::<_, mlua::Table>("package")?.get::<_, String>("path")?;
+        l.load(RESTRICT).set_name("=sandbox")?.call::<_, ()>(path)?;
+    }
+
+    if let Some(max) = limits.instructions {
+        l.set_app_data(Budget { max, used: AtomicU64::new(0) });
+        l.set_hook(HookTriggers { every_nth_instruction: Some(CHECK_EVERY), ..Default::default() }, |l, _| {
+            let budget = match l.app_data_ref::<Budget>() {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            // Keeps failing until the next refill, so pcall can't get around it
+            let used = budget.used.fetch_add(CHECK_EVERY as u64, Ordering::Relaxed) + CHECK_EVERY as u64;
+            if used > budget.max {
+                return Err(mlua::Error::RuntimeError(format!("script ran for more than {} instructions", budget.max)));
+            }
+            Ok(())
+        })?;
+    }
+
+    if let Some(bytes) = limits.memory {
+        l.set_memory_limit(bytes)?;
+    }
+
+    Ok(())
+}
+
+/// Gives the script a full instruction budget, for handling a new message.
+pub fn refill(l: &mlua::Lua) {
+    if let Some(budget) = l.app_data_ref::<Budget>() {
+        budget.used.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::{output::RecordingBackend, script::Script};
+    use super::*;
+
+    fn sandboxed() -> Script {
+        let limits = Limits { sandbox: true, ..Default::default() };
+        let mut script = Script::new("test".into(), None, limits);
+        let (sender, _) = tokio::sync::mpsc::unbounded_channel();
+        script.start(Arc::new(RecordingBackend::new(false)), sender).unwrap();
+        script
+    }
+
+    fn eval<T: for<'a> mlua::FromLuaMulti<'a>>(script: &Script, code: &str) -> mlua::Result<T> {
+        script.lua.as_ref().unwrap().load(code).eval()
+    }
+
+    #[test]
+    fn load_keeps_globals() {
+        let script = sandboxed();
+        assert_eq!(eval::<i32>(&script, r#"return load("return math.max(1, 2)")()"#).unwrap(), 2);
+        assert_eq!(eval::<i32>(&script, r#"return load("return x", "x", "t", { x = 3 })()"#).unwrap(), 3);
+        assert!(eval::<()>(&script, r#"return load("return math", "x", "t", nil)().pi"#).is_err());
+        assert!(eval::<bool>(&script, r#"return load(string.dump(function() end)) == nil"#).unwrap());
+    }
+
+    #[test]
+    fn package_path_is_fixed() {
+        let dir = std::env::temp_dir().join(format!("handcake-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("secret"), "root:x:0:0:root:/root:/bin/sh\n").unwrap();
+
+        let script = sandboxed();
+        let code = format!(r#"package.path = "{}/?"; return select(2, pcall(require, "secret"))"#, dir.display());
+        let err = eval::<String>(&script, &code).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(err.contains("module 'secret' not found"), "{}", err);
+        assert!(!err.contains("/bin/sh"), "{}", err);
+    }
+
+    #[test]
+    fn osc_stays_on_this_machine() {
+        let script = sandboxed();
+        let err = eval::<()>(&script, r#"osc.listen(9000, "0.0.0.0")"#).unwrap_err();
+        assert!(err.to_string().contains("can't listen for OSC on 0.0.0.0"), "{}", err);
+        let err = eval::<()>(&script, r#"osc.send("192.0.2.1", 9000, "/x")"#).unwrap_err();
+        assert!(err.to_string().contains("can't send OSC to 192.0.2.1"), "{}", err);
+    }
+
+    #[test]
+    fn no_keyboards() {
+        let script = sandboxed();
+        let err = eval::<()>(&script, "keyboard.create()").unwrap_err();
+        assert!(err.to_string().contains("can't create a keyboard"), "{}", err);
+    }
+}
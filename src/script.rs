@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::{AtomicU32, Ordering}},
+};
+use parking_lot::Mutex;
+
+use crate::{Message, MessageSender, RunArgs, config, output::OutputBackend, sandbox::{self, Limits}};
+
+/// Stored in each script's Lua state, so the APIs can tell scripts apart.
+struct ScriptId(u32);
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+lazy_static::lazy_static! {
+    /// Names of running scripts by id.
+    static ref NAMES: Mutex<HashMap<u32, String>> = Mutex::new(HashMap::new());
+}
+
+/// Which script a Lua state belongs to. Every start gets a new id, so
+/// nothing left over from an old run can be mistaken for the new one.
+pub fn id(l: &mlua::Lua) -> u32 {
+    l.app_data_ref::<ScriptId>().map(|x| x.0).unwrap_or(0)
+}
+
+/// Where the script's APIs send things for the dispatcher, like MIDI from an
+/// opened port.
+pub fn sender(l: &mlua::Lua) -> MessageSender {
+    l.app_data_ref::<MessageSender>().expect("Lua state was not made by Script::start").clone()
+}
+
+pub fn name(id: u32) -> Option<String> {
+    NAMES.lock().get(&id).cloned()
+}
+
+#[derive(Clone)]
+pub enum Source {
+    Script(PathBuf),
+    Config(PathBuf),
+}
+
+impl Source {
+    /// Every script (or the config) asked for on the command line. Directories
+    /// are expanded to the `.lua` files in them.
+    pub fn from_args(run: &RunArgs) -> anyhow::Result<Vec<Self>> {
+        if let Some(path) = &run.config {
+            return Ok(vec![Source::Config(path.clone())]);
+        }
+
+        let mut out = vec![];
+        for path in &run.script {
+            if path.is_dir() {
+                let mut files = std::fs::read_dir(path)?
+                    .filter_map(|x| x.ok())
+                    .map(|x| x.path())
+                    .filter(|x| x.is_file() && x.extension().is_some_and(|x| x == "lua"))
+                    .collect::<Vec<_>>();
+                files.sort();
+                if files.is_empty() {
+                    warn!("No scripts in {:?}", path);
+                }
+                out.extend(files.into_iter().map(Source::Script));
+            } else if path.exists() {
+                out.push(Source::Script(path.clone()));
+            } else {
+                anyhow::bail!("Script at path {:?} does not exist, aborting.", path);
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Source::Script(x) | Source::Config(x) => x,
+        }
+    }
+
+    fn dir(&self) -> PathBuf {
+        match self.path().parent() {
+            Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+}
+
+/// A script or config running in its own Lua state, with its own devices
+/// and callbacks.
+pub struct Script {
+    pub name: String,
+    /// `None` for a state with just the APIs, like tests without a script.
+    source: Option<Source>,
+    limits: Limits,
+    /// `None` while the script is disabled.
+    pub lua: Option<mlua::Lua>,
+}
+
+impl Script {
+    pub fn new(name: String, source: Option<Source>, limits: Limits) -> Self {
+        Self { name, source, limits, lua: None }
+    }
+
+    /// Sets up a Lua state with every API registered, then loads the config
+    /// or script and runs `on_script_init()`. A running script is only
+    /// replaced once that works, so a broken reload leaves it going.
+    pub fn start(&mut self, backend: Arc<dyn OutputBackend>, sender: MessageSender) -> anyhow::Result<()> {
+        let lua = self.build(backend, sender)?;
+        self.stop();
+        self.lua = Some(lua);
+        Ok(())
+    }
+
+    fn build(&self, backend: Arc<dyn OutputBackend>, sender: MessageSender) -> anyhow::Result<mlua::Lua> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let lua = mlua::Lua::new();
+        lua.set_app_data(ScriptId(id));
+        lua.set_app_data(sender);
+        NAMES.lock().insert(id, self.name.clone());
+        crate::register_apis(&lua, backend, self.source.as_ref().map(|x| x.dir()));
+
+        let res = sandbox::apply(&lua, &self.limits)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| self.load(&lua));
+        if let Err(e) = res {
+            NAMES.lock().remove(&id);
+            return Err(e);
+        }
+
+        Ok(lua)
+    }
+
+    fn load(&self, lua: &mlua::Lua) -> anyhow::Result<()> {
+        match &self.source {
+            None => {},
+            Some(Source::Config(path)) => {
+                info!("Loading config {:?}", path);
+                let config = config::load(path).map_err(|e| anyhow::anyhow!("Could not load config {:?}: {}", path, e))?;
+                debug!("Applying config");
+                config::apply(lua, &config)?;
+            },
+            Some(Source::Script(path)) => {
+                info!("Running script {:?}", path);
+                let script_text = std::fs::read_to_string(path)?;
+                let mut a = lua.load(&script_text);
+                if self.limits.sandbox {
+                    a = a.set_mode(mlua::ChunkMode::Text);
+                }
+                let a = a.set_name(&path.to_string_lossy().as_bytes())?;
+
+                debug!("Evaluating initial script");
+
+                a.exec()?;
+                debug!("Calling on_script_init()");
+
+                let globals = &lua.globals();
+                let on_script_init = globals.get::<&str, mlua::Function>("on_script_init")?;
+                on_script_init.call::<(), ()>(())?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Throws away the Lua state along with its devices and bindings.
+    pub fn stop(&mut self) {
+        if let Some(lua) = self.lua.take() {
+            NAMES.lock().remove(&id(&lua));
+        }
+    }
+}
+
+/// Every script that was asked for, where they make their devices and where
+/// they send messages.
+pub struct Scripts {
+    pub scripts: Vec<Script>,
+    backend: Arc<dyn OutputBackend>,
+    sender: MessageSender,
+}
+
+impl Scripts {
+    /// Starts every script. Any of them failing is fatal, since this is
+    /// what the user asked for on the command line.
+    pub fn start(run: &RunArgs, backend: Arc<dyn OutputBackend>, sender: MessageSender) -> anyhow::Result<Self> {
+        let limits = Limits::from_args(run);
+        let mut scripts: Vec<Script> = vec![];
+        for source in Source::from_args(run)? {
+            let path = source.path();
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let name = if scripts.iter().any(|x| x.name == stem) {
+                path.to_string_lossy().to_string()
+            } else {
+                stem
+            };
+
+            let mut script = Script::new(name, Some(source), limits);
+            script.start(backend.clone(), sender.clone())?;
+            scripts.push(script);
+        }
+
+        Ok(Self { scripts, backend, sender })
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Result<&mut Script, String> {
+        self.scripts.iter_mut().find(|x| x.name == name).ok_or_else(|| format!("no script called {}", name))
+    }
+
+    pub fn running(&self) -> impl Iterator<Item = (&str, &mlua::Lua)> {
+        self.scripts.iter().filter_map(|x| x.lua.as_ref().map(|l| (x.name.as_str(), l)))
+    }
+
+    pub fn enable(&mut self, name: &str) -> anyhow::Result<()> {
+        let (backend, sender) = (self.backend.clone(), self.sender.clone());
+        let script = self.get_mut(name).map_err(|e| anyhow::anyhow!(e))?;
+        if script.lua.is_none() {
+            info!("Enabling {}", name);
+            script.start(backend, sender)?;
+        }
+        Ok(())
+    }
+
+    pub fn disable(&mut self, name: &str) -> Result<(), String> {
+        let script = self.get_mut(name)?;
+        if script.lua.is_some() {
+            info!("Disabling {}", name);
+            script.stop();
+        }
+        Ok(())
+    }
+
+    /// Restarts one script, or all of the running ones. Scripts that fail
+    /// to come back up keep running as they were.
+    pub fn reload(&mut self, name: Option<&str>) -> anyhow::Result<()> {
+        let (backend, sender) = (self.backend.clone(), self.sender.clone());
+        if let Some(name) = name {
+            let script = self.get_mut(name).map_err(|e| anyhow::anyhow!(e))?;
+            info!("Reloading {}", name);
+            return script.start(backend, sender);
+        }
+
+        let mut errors = vec![];
+        for script in self.scripts.iter_mut().filter(|x| x.lua.is_some()) {
+            info!("Reloading {}", script.name);
+            if let Err(e) = script.start(backend.clone(), sender.clone()) {
+                errors.push(format!("{}: {}", script.name, e));
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!(errors.join("; "))),
+        }
+    }
+
+    /// Handles one message. Returns false when it's time to stop.
+    pub fn handle(&mut self, msg: Message) -> bool {
+        for (_, lua) in self.running() {
+            sandbox::refill(lua);
+        }
+
+        match msg {
+            Message::Quit => return false,
+            Message::Ipc(req) => {
+                let res = crate::ipc::handle(self, req.command);
+                let _ = req.reply.send(res);
+            },
+            #[cfg(feature = "web")]
+            Message::Web(client, data) => {
+                if let Err(e) = crate::web::handle(self, client, data) {
+                    error!("Script error: {}", e);
+                }
+            },
+            msg => {
+                for (name, lua) in self.running() {
+                    if let Err(e) = crate::dispatch(lua, &msg) {
+                        error!("Script error in {}: {}", name, e);
+                    }
+                }
+                #[cfg(feature = "web")]
+                if matches!(msg, Message::Tick) {
+                    crate::web::update(self);
+                }
+            },
+        }
+
+        true
+    }
+}
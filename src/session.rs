@@ -0,0 +1,125 @@
+use std::{
+    io::{BufRead, BufReader, BufWriter, Write},
+    fs::File,
+    path::Path,
+    time::{Duration, Instant},
+};
+use midir::Ignore;
+
+use crate::{Message, MessageSender, TICK_INTERVAL, api::{misc, midi::{MidiTime, find_port, to_message}}};
+
+// Each line of a session log is the time since the start of the session in
+// microseconds, followed by the raw MIDI bytes in hex: `1523 90 25 64`
+const HEADER: &str = "# handcake session log v1";
+
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+pub struct LoggedEvent {
+    pub t: Duration,
+    pub data: Vec<u8>,
+}
+
+pub fn read_log(path: &Path) -> anyhow::Result<Vec<LoggedEvent>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = vec![];
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+        let parsed = (|| {
+            let t = parts.next()?.parse::<u64>().ok()?;
+            let data = parts.map(|x| u8::from_str_radix(x, 16).ok()).collect::<Option<Vec<u8>>>()?;
+            Some(LoggedEvent { t: Duration::from_micros(t), data })
+        })();
+
+        match parsed {
+            Some(x) => events.push(x),
+            None => anyhow::bail!("{:?} line {}: can't parse '{}'", path, i + 1, line),
+        }
+    }
+
+    Ok(events)
+}
+
+fn write_event(out: &mut impl Write, t: Duration, data: &[u8]) -> std::io::Result<()> {
+    write!(out, "{}", t.as_micros())?;
+    for b in data {
+        write!(out, " {:02x}", b)?;
+    }
+    writeln!(out)
+}
+
+/// Logs everything from a MIDI port until interrupted.
+pub async fn record(portno: usize, out: &Path) -> anyhow::Result<()> {
+    let mut midi_in = midir::MidiInput::new("handcake MIDI recorder")?;
+    midi_in.ignore(Ignore::None);
+    let port = find_port(&midi_in, portno)?;
+    let name = midi_in.port_name(&port)?;
+
+    let mut file = BufWriter::new(File::create(out)?);
+    writeln!(file, "{}", HEADER)?;
+    writeln!(file, "# recorded from {}", name)?;
+    file.flush()?;
+
+    let start = Instant::now();
+    let conn = midi_in.connect(&port, &name, move |_ts, data, file| {
+        let res = write_event(file, start.elapsed(), data).and_then(|_| file.flush());
+        if let Err(e) = res {
+            error!("Could not write to session log: {}", e);
+        }
+    }, file).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    info!("Recording from {} to {:?}, press Ctrl-C to stop", name, out);
+    tokio::signal::ctrl_c().await?;
+
+    let (_, mut file) = conn.close();
+    file.flush()?;
+    info!("Recording stopped after {:.1}s", start.elapsed().as_secs_f32());
+
+    Ok(())
+}
+
+/// Feeds logged events into the dispatcher with their original timing,
+/// then tells it to stop. Time is simulated, moved on by the ticks sent in
+/// between, so a replay runs as fast as it can and comes out the same
+/// every time.
+pub fn replay(events: Vec<LoggedEvent>, speed: f32, sender: MessageSender) {
+    // Nothing moves the clock until the first tick is sent
+    let start = misc::now();
+    let mut ticks = 0;
+    let mut tick_until = |t: Duration| {
+        while TICK_INTERVAL * ticks < t {
+            if sender.send(Message::Tick).is_err() {
+                return false;
+            }
+            ticks += 1;
+        }
+        true
+    };
+
+    let mut end = Duration::ZERO;
+    for ev in events {
+        let due = ev.t.div_f32(speed);
+        if !tick_until(due) {
+            return;
+        }
+
+        // The log's own times stand in for the device's, sped up along with
+        // everything else so the tempo comes out right
+        let time = MidiTime { device: due.as_micros() as u64, received: start + due, played: false };
+        if sender.send(to_message(&ev.data, time)).is_err() {
+            return;
+        }
+        end = due;
+    }
+
+    // Give anything time-based (filters, turbo) a moment to catch up
+    if tick_until(end + SETTLE_TIME) {
+        let _ = sender.send(Message::Quit);
+    }
+}
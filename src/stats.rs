@@ -0,0 +1,200 @@
+use std::{sync::atomic::{AtomicBool, Ordering}, time::{Duration, Instant}};
+use parking_lot::Mutex;
+
+// Measures how long MIDI takes to make it out of a virtual device: from when
+// the port got it (`MidiTime::received`) to the first uinput write it caused.
+// Messages that don't write anything aren't counted, and neither is output
+// from ticks, like filters catching up afterwards.
+
+/// How often percentiles are logged with `--stats`.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Priority of the dispatcher thread with `--realtime`. High enough to get
+/// ahead of normal processes, below the IRQ threads and audio servers.
+const REALTIME_PRIORITY: i32 = 40;
+
+/// Histogram buckets per doubling, so percentiles over the whole run are
+/// within about 6%.
+const SUB_BUCKETS: u32 = 16;
+const BUCKETS: usize = (32 - SUB_BUCKETS.trailing_zeros() as usize + 1) * SUB_BUCKETS as usize;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Microsecond samples in log-spaced buckets, so a long run takes no more
+/// memory than a short one.
+struct Histogram {
+    counts: [u64; BUCKETS],
+    len: u64,
+    max: u32,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { counts: [0; BUCKETS], len: 0, max: 0 }
+    }
+}
+
+impl Histogram {
+    fn bucket(x: u32) -> usize {
+        if x < SUB_BUCKETS {
+            return x as usize;
+        }
+        let shift = (31 - x.leading_zeros()) - SUB_BUCKETS.trailing_zeros();
+        ((shift + 1) * SUB_BUCKETS + ((x >> shift) - SUB_BUCKETS)) as usize
+    }
+
+    /// The smallest value that lands in a bucket.
+    fn lowest(bucket: usize) -> u32 {
+        let (octave, sub) = (bucket as u32 / SUB_BUCKETS, bucket as u32 % SUB_BUCKETS);
+        match octave {
+            0 => sub,
+            _ => (SUB_BUCKETS + sub) << (octave - 1),
+        }
+    }
+
+    fn push(&mut self, x: u32) {
+        self.counts[Self::bucket(x)] += 1;
+        self.len += 1;
+        self.max = self.max.max(x);
+    }
+
+    /// In ms, from the middle of the bucket it falls in.
+    fn percentile(&self, p: f64) -> f64 {
+        let rank = ((self.len - 1) as f64 * p / 100.0).round() as u64;
+        if rank == self.len - 1 {
+            return self.max as f64 / 1000.0;
+        }
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate().take(BUCKETS - 1) {
+            seen += n;
+            if seen > rank {
+                let middle = Self::lowest(i) as f64 / 2.0 + Self::lowest(i + 1) as f64 / 2.0;
+                return middle.min(self.max as f64) / 1000.0;
+            }
+        }
+        self.max as f64 / 1000.0
+    }
+}
+
+#[derive(Default)]
+struct Samples {
+    /// When the message being dispatched arrived, until it writes something.
+    pending: Option<Instant>,
+    /// In microseconds, since the last report.
+    queued: Vec<u32>,
+    total: Vec<u32>,
+    /// Everything since starting, for the report at the end.
+    all_queued: Histogram,
+    all_total: Histogram,
+}
+
+lazy_static::lazy_static! {
+    static ref SAMPLES: Mutex<Samples> = Mutex::new(Samples::default());
+}
+
+fn micros(since: Instant) -> u32 {
+    since.elapsed().as_micros().min(u32::MAX as u128) as u32
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The dispatcher is about to handle MIDI that arrived at `received`.
+pub fn begin(received: Instant) {
+    if !enabled() {
+        return;
+    }
+    let mut samples = SAMPLES.lock();
+    let queued = micros(received);
+    samples.queued.push(queued);
+    samples.all_queued.push(queued);
+    samples.pending = Some(received);
+}
+
+/// The message is done with, whether or not it wrote anything.
+pub fn end() {
+    if enabled() {
+        SAMPLES.lock().pending = None;
+    }
+}
+
+/// Called by output devices after every write.
+pub fn written() {
+    if !enabled() {
+        return;
+    }
+    let mut samples = SAMPLES.lock();
+    if let Some(received) = samples.pending.take() {
+        let total = micros(received);
+        samples.total.push(total);
+        samples.all_total.push(total);
+    }
+}
+
+/// Takes the number of samples and a way to get percentiles of them in ms.
+fn summary(len: u64, percentile: impl Fn(f64) -> f64) -> String {
+    if len == 0 {
+        return "nothing yet".into();
+    }
+    format!("p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, p99.9 {:.3}ms, max {:.3}ms",
+        percentile(50.0), percentile(90.0), percentile(99.0), percentile(99.9), percentile(100.0))
+}
+
+fn exact(samples: &mut [u32]) -> String {
+    samples.sort_unstable();
+    summary(samples.len() as u64, |p| {
+        let idx = ((samples.len() - 1) as f64 * p / 100.0).round() as usize;
+        samples[idx] as f64 / 1000.0
+    })
+}
+
+fn log(what: &str, messages: u64, with_output: u64, total: String, queued: String) {
+    info!("Latency {} ({} MIDI messages, {} with output)", what, messages, with_output);
+    info!("  MIDI to output: {}", total);
+    info!("  waiting to be dispatched: {}", queued);
+}
+
+/// Logs what's been measured since the last report.
+pub fn report() {
+    let mut samples = SAMPLES.lock();
+    if samples.queued.is_empty() {
+        return;
+    }
+    let (mut queued, mut total) = (std::mem::take(&mut samples.queued), std::mem::take(&mut samples.total));
+    drop(samples);
+    let (messages, with_output) = (queued.len() as u64, total.len() as u64);
+    log(&format!("over the last {}s", REPORT_INTERVAL.as_secs()), messages, with_output, exact(&mut total), exact(&mut queued));
+}
+
+/// Logs everything measured since starting.
+pub fn report_all() {
+    let samples = SAMPLES.lock();
+    let (queued, total) = (&samples.all_queued, &samples.all_total);
+    log("overall", queued.len, total.len,
+        summary(total.len, |p| total.percentile(p)), summary(queued.len, |p| queued.percentile(p)));
+}
+
+/// Gives the calling thread realtime priority and keeps all of handcake's
+/// memory paged in, so neither a busy system nor swapping adds latency.
+/// Failing either isn't fatal, things just run as they would have.
+pub fn go_realtime() {
+    let param = libc::sched_param { sched_priority: REALTIME_PRIORITY };
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } != 0 {
+        warn!("Could not switch to realtime scheduling: {}. This needs CAP_SYS_NICE or an rtprio limit \
+            (see limits.conf)", std::io::Error::last_os_error());
+    } else {
+        info!("Dispatching with SCHED_FIFO priority {}", REALTIME_PRIORITY);
+    }
+
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        warn!("Could not lock memory: {}. This needs CAP_IPC_LOCK or a memlock limit \
+            (see limits.conf)", std::io::Error::last_os_error());
+    } else {
+        debug!("Memory locked");
+    }
+}
@@ -0,0 +1,217 @@
+use std::sync::Arc;
+use mlua::{Lua, RegistryKey};
+
+#[derive(Clone)]
+pub enum DeadzoneMode {
+    Radial,
+    Axial,
+}
+
+#[derive(Clone)]
+pub enum Curve {
+    Linear,
+    Exponential(f32),
+    Custom(Arc<RegistryKey>),
+}
+
+#[derive(Clone)]
+pub struct StickConfig {
+    pub deadzone: f32,
+    pub outer_deadzone: f32,
+    pub mode: DeadzoneMode,
+    pub curve: Curve,
+    pub anti_deadzone: f32,
+    pub square: bool,
+}
+
+impl Default for StickConfig {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.0,
+            outer_deadzone: 1.0,
+            mode: DeadzoneMode::Radial,
+            curve: Curve::Linear,
+            anti_deadzone: 0.0,
+            square: false,
+        }
+    }
+}
+
+impl StickConfig {
+    pub fn from_table(l: &Lua, tab: mlua::Table) -> mlua::Result<Self> {
+        let mut cfg = Self::default();
+
+        if let Some(x) = tab.get::<_, Option<f32>>("deadzone")? {
+            cfg.deadzone = x.clamp(0.0, 1.0);
+        }
+        if let Some(x) = tab.get::<_, Option<f32>>("outer_deadzone")? {
+            cfg.outer_deadzone = x.clamp(0.0, 1.0);
+        }
+        if let Some(x) = tab.get::<_, Option<f32>>("anti_deadzone")? {
+            cfg.anti_deadzone = x.clamp(0.0, 1.0);
+        }
+        if let Some(x) = tab.get::<_, Option<bool>>("square")? {
+            cfg.square = x;
+        }
+        if let Some(x) = tab.get::<_, Option<String>>("deadzone_mode")? {
+            cfg.mode = match x.as_str() {
+                "radial" => DeadzoneMode::Radial,
+                "axial" => DeadzoneMode::Axial,
+                _ => return Err(mlua::Error::RuntimeError(format!("unknown deadzone mode '{}'", x))),
+            };
+        }
+
+        let exponent = tab.get::<_, Option<f32>>("exponent")?.unwrap_or(2.0);
+        cfg.curve = match tab.get::<_, mlua::Value>("curve")? {
+            mlua::Value::Nil => Curve::Linear,
+            mlua::Value::Function(f) => Curve::Custom(Arc::new(l.create_registry_value(f)?)),
+            mlua::Value::String(s) => match s.to_str()? {
+                "linear" => Curve::Linear,
+                "exponential" => Curve::Exponential(exponent),
+                x => return Err(mlua::Error::RuntimeError(format!("unknown curve '{}'", x))),
+            },
+            _ => return Err(mlua::Error::RuntimeError("curve must be a string or a function".into())),
+        };
+
+        if cfg.outer_deadzone <= cfg.deadzone {
+            return Err(mlua::Error::RuntimeError("outer_deadzone must be larger than deadzone".into()));
+        }
+
+        Ok(cfg)
+    }
+
+    /// Maps a magnitude in 0..1 through the deadzones, curve and anti-deadzone.
+    fn shape(&self, l: &Lua, mag: f32) -> mlua::Result<f32> {
+        if mag <= self.deadzone {
+            return Ok(0.0);
+        }
+        let scaled = ((mag - self.deadzone) / (self.outer_deadzone - self.deadzone)).clamp(0.0, 1.0);
+
+        let curved = match &self.curve {
+            Curve::Linear => scaled,
+            Curve::Exponential(e) => scaled.powf(*e),
+            Curve::Custom(key) => {
+                let f = l.registry_value::<mlua::Function>(key)?;
+                f.call::<_, f32>((scaled,))?.clamp(0.0, 1.0)
+            },
+        };
+
+        if curved <= 0.0 {
+            return Ok(0.0);
+        }
+        Ok(self.anti_deadzone + (1.0 - self.anti_deadzone) * curved)
+    }
+
+    pub fn process(&self, l: &Lua, x: f32, y: f32) -> mlua::Result<(f32, f32)> {
+        let (x, y) = (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0));
+
+        let (x, y) = match self.mode {
+            DeadzoneMode::Radial => {
+                let mag = (x * x + y * y).sqrt();
+                if mag <= f32::EPSILON {
+                    (0.0, 0.0)
+                } else {
+                    let out = self.shape(l, mag.min(1.0))?;
+                    (x / mag * out, y / mag * out)
+                }
+            },
+            DeadzoneMode::Axial => {
+                (self.shape(l, x.abs())?.copysign(x), self.shape(l, y.abs())?.copysign(y))
+            },
+        };
+
+        if self.square {
+            Ok(circle_to_square(x, y))
+        } else {
+            Ok((x, y))
+        }
+    }
+}
+
+/// Elliptical grid mapping, so that a stick pushed to the edge of its
+/// circular gate can reach the corners of the square output range.
+fn circle_to_square(u: f32, v: f32) -> (f32, f32) {
+    let two_sqrt2 = 2.0 * std::f32::consts::SQRT_2;
+    let (u2, v2) = (u * u, v * v);
+
+    let x = 0.5 * (2.0 + u2 - v2 + two_sqrt2 * u).max(0.0).sqrt()
+        - 0.5 * (2.0 + u2 - v2 - two_sqrt2 * u).max(0.0).sqrt();
+    let y = 0.5 * (2.0 - u2 + v2 + two_sqrt2 * v).max(0.0).sqrt()
+        - 0.5 * (2.0 - u2 + v2 - two_sqrt2 * v).max(0.0).sqrt();
+
+    (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(a: (f32, f32), b: (f32, f32)) -> bool {
+        (a.0 - b.0).abs() < 1e-3 && (a.1 - b.1).abs() < 1e-3
+    }
+
+    fn config(l: &Lua, code: &str) -> mlua::Result<StickConfig> {
+        StickConfig::from_table(l, l.load(code).eval()?)
+    }
+
+    #[test]
+    fn radial_deadzone() {
+        let l = Lua::new();
+        let cfg = config(&l, "{ deadzone = 0.2 }").unwrap();
+        assert_eq!(cfg.process(&l, 0.1, 0.1).unwrap(), (0.0, 0.0));
+        // Just the magnitude is rescaled, so the direction stays put
+        assert!(near(cfg.process(&l, 0.6, 0.0).unwrap(), (0.5, 0.0)));
+        assert!(near(cfg.process(&l, 0.0, -1.0).unwrap(), (0.0, -1.0)));
+        let (x, y) = cfg.process(&l, 0.3, 0.4).unwrap();
+        assert!((x / y - 0.75).abs() < 1e-4);
+    }
+
+    #[test]
+    fn axial_deadzone() {
+        let l = Lua::new();
+        let cfg = config(&l, "{ deadzone = 0.2, deadzone_mode = 'axial' }").unwrap();
+        // Each axis has its own, so being far out on one doesn't free the other
+        assert!(near(cfg.process(&l, 0.9, 0.1).unwrap(), (0.875, 0.0)));
+        assert!(near(cfg.process(&l, -0.6, 0.6).unwrap(), (-0.5, 0.5)));
+    }
+
+    #[test]
+    fn outer_and_anti_deadzones() {
+        let l = Lua::new();
+        let cfg = config(&l, "{ outer_deadzone = 0.8, anti_deadzone = 0.1 }").unwrap();
+        assert_eq!(cfg.process(&l, 0.0, 0.0).unwrap(), (0.0, 0.0));
+        assert!(near(cfg.process(&l, 0.9, 0.0).unwrap(), (1.0, 0.0)));
+        assert!(near(cfg.process(&l, 0.4, 0.0).unwrap(), (0.55, 0.0)));
+    }
+
+    #[test]
+    fn curves() {
+        let l = Lua::new();
+        let cfg = config(&l, "{ curve = 'exponential', exponent = 3 }").unwrap();
+        assert!(near(cfg.process(&l, 0.5, 0.0).unwrap(), (0.125, 0.0)));
+        assert!(near(cfg.process(&l, -1.0, 0.0).unwrap(), (-1.0, 0.0)));
+
+        let cfg = config(&l, "{ curve = function(x) return x * 2 end }").unwrap();
+        assert!(near(cfg.process(&l, 0.25, 0.0).unwrap(), (0.5, 0.0)));
+        assert!(near(cfg.process(&l, 0.75, 0.0).unwrap(), (1.0, 0.0)));
+    }
+
+    #[test]
+    fn circle_to_square_reaches_corners() {
+        let d = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(near(circle_to_square(d, d), (1.0, 1.0)));
+        assert!(near(circle_to_square(-d, d), (-1.0, 1.0)));
+        assert!(near(circle_to_square(1.0, 0.0), (1.0, 0.0)));
+        assert!(near(circle_to_square(0.0, -1.0), (0.0, -1.0)));
+        assert_eq!(circle_to_square(0.0, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn bad_tables() {
+        let l = Lua::new();
+        assert!(config(&l, "{ deadzone = 0.5, outer_deadzone = 0.4 }").is_err());
+        assert!(config(&l, "{ deadzone_mode = 'square' }").is_err());
+        assert!(config(&l, "{ curve = 'cubic' }").is_err());
+        assert!(config(&l, "{ curve = 3 }").is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use std::{path::Path, sync::Arc};
+
+use crate::{MessageReceiver, RunArgs, api::{self, ApiProvider}, output::RecordingBackend, sandbox::Limits, script::{Script, Source}};
+
+struct Outcome {
+    name: String,
+    result: mlua::Result<()>,
+}
+
+/// Sets up a fresh state with the script under test and the test file
+/// loaded, then runs the test numbered `idx` (from 1) if there is one.
+/// Returns how many tests the file has along with the outcome.
+fn run_test(run: &RunArgs, file: &Path, idx: usize) -> anyhow::Result<(usize, Option<Outcome>)> {
+    // Nothing from a previous test should leak into this one
+    crate::focus::clear();
+    api::misc::simulate();
+    let source = Source::from_args(run)?.into_iter().next();
+    let mut script = Script::new("test".into(), source, Limits::default());
+    let backend = Arc::new(RecordingBackend::new(false));
+    // Anything the script sends itself, like macro MIDI, waits here until
+    // sim.advance picks it up
+    let (sender, events) = tokio::sync::mpsc::unbounded_channel();
+    script.start(backend.clone(), sender)?;
+    let res = run_in(script.lua.as_ref().unwrap(), backend, events, file, idx);
+    script.stop();
+    res
+}
+
+fn run_in(lua: &mlua::Lua, backend: Arc<RecordingBackend>, events: MessageReceiver, file: &Path, idx: usize) -> anyhow::Result<(usize, Option<Outcome>)> {
+    api::sim::Sim::register_api(lua, (backend, events))?;
+
+    let text = std::fs::read_to_string(file)?;
+    lua.load(&text).set_name(&file.to_string_lossy().as_bytes())?.exec()?;
+
+    let tests = lua.globals().get::<_, mlua::Table>("_tests")?;
+    let total = tests.raw_len() as usize;
+    if idx > total {
+        return Ok((total, None));
+    }
+
+    let test = tests.get::<_, mlua::Table>(idx)?;
+    let name = test.get::<_, String>("name")?;
+    let result = test.get::<_, mlua::Function>("f")?.call::<_, ()>(());
+
+    Ok((total, Some(Outcome { name, result })))
+}
+
+/// Runs every test in every file, each against its own copy of the script.
+/// Returns whether they all passed.
+pub fn run(files: &[impl AsRef<Path>], run: &RunArgs) -> bool {
+    let mut passed = 0;
+    let mut failed = 0;
+
+    for file in files {
+        let file = file.as_ref();
+        let mut idx = 1;
+        loop {
+            match run_test(run, file, idx) {
+                Ok((_, Some(outcome))) => {
+                    match outcome.result {
+                        Ok(()) => {
+                            println!("test {} :: {} ... ok", file.display(), outcome.name);
+                            passed += 1;
+                        },
+                        Err(e) => {
+                            let e = e.to_string().replace('\n', "\n    ");
+                            println!("test {} :: {} ... FAILED\n    {}", file.display(), outcome.name, e);
+                            failed += 1;
+                        },
+                    }
+                },
+                Ok((total, None)) => {
+                    if total == 0 {
+                        println!("{}: no tests found", file.display());
+                    }
+                    break;
+                },
+                Err(e) => {
+                    println!("{}: could not set up test {}: {}", file.display(), idx, e);
+                    failed += 1;
+                    break;
+                },
+            }
+            idx += 1;
+        }
+    }
+
+    println!();
+    println!("test result: {}. {} passed, {} failed", if failed == 0 { "ok" } else { "FAILED" }, passed, failed);
+
+    failed == 0
+}
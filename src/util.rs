@@ -1,4 +1,21 @@
-use midi_control::Channel;
+use std::{fs::File, os::unix::prelude::OpenOptionsExt, path::Path};
+use input_linux::UInputHandle;
+use midi_control::{Channel, MidiMessage, consts};
+
+pub fn open_uinput() -> anyhow::Result<UInputHandle<File>> {
+    let uinput_path = Path::new("/dev").join("uinput");
+    if !uinput_path.exists() {
+        anyhow::bail!("Could not find /dev/uinput. Is uinput installed?");
+    }
+    let fd = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(uinput_path)?;
+
+    Ok(UInputHandle::new(fd))
+}
+
 
 pub fn midi_channel_to_num(ch: &Channel) -> i8 {
     match ch {
@@ -20,4 +37,29 @@ pub fn midi_channel_to_num(ch: &Channel) -> i8 {
         Channel::Ch16 => 16,
         Channel::Invalid => -1,
     }
-}
\ No newline at end of file
+}
+
+/// Like `MidiMessage::from`, which takes anything shorter than 3 bytes to be
+/// invalid, except that program change and channel pressure are 2.
+pub fn bytes_to_midi(data: &[u8]) -> MidiMessage {
+    match data {
+        [status, p] if status & 0xf0 == consts::PROGRAM_CHANGE => MidiMessage::ProgramChange(Channel::from(status & 0x0f), *p),
+        [status, p] if status & 0xf0 == consts::CHANNEL_KEY_PRESSURE => MidiMessage::ChannelPressure(Channel::from(status & 0x0f), *p),
+        _ => MidiMessage::from(data),
+    }
+}
+
+pub fn midi_to_bytes(msg: &MidiMessage) -> Vec<u8> {
+    let ch = |c: &Channel| *c as u8;
+    match msg {
+        MidiMessage::NoteOff(c, e) => vec![consts::NOTE_OFF | ch(c), e.key, e.value],
+        MidiMessage::NoteOn(c, e) => vec![consts::NOTE_ON | ch(c), e.key, e.value],
+        MidiMessage::PolyKeyPressure(c, e) => vec![consts::POLYPHONIC_KEY_PRESSURE | ch(c), e.key, e.value],
+        MidiMessage::ControlChange(c, e) => vec![consts::CONTROL_CHANGE | ch(c), e.control, e.value],
+        MidiMessage::ProgramChange(c, p) => vec![consts::PROGRAM_CHANGE | ch(c), *p],
+        MidiMessage::ChannelPressure(c, p) => vec![consts::CHANNEL_KEY_PRESSURE | ch(c), *p],
+        MidiMessage::PitchBend(c, lsb, msb) => vec![consts::PITCH_BEND_CHANGE | ch(c), *lsb, *msb],
+        // Not worth rebuilding
+        MidiMessage::SysEx(_) | MidiMessage::Invalid => vec![],
+    }
+}
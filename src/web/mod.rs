@@ -0,0 +1,354 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use futures_util::{SinkExt, StreamExt};
+use mlua::LuaSerdeExt;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use crate::{Message, MessageSender, api, script::Scripts};
+
+// Browsers talk to the scripts over a WebSocket at any path, sending JSON
+// objects. Scripts get them through `on_web_recv(msg, client)`; without one,
+// `{"type": "button", "code": 304, "pressed": true}` and
+// `{"type": "axis", "code": 0, "value": -0.5}` drive gamepad `pad` (default 1)
+// directly. Clients are sent `{"type": "state", ...}` whenever the devices change.
+// When a client goes away, scripts get `{"type": "disconnect"}` from it, and
+// otherwise whatever it was holding is let go.
+
+const INDEX: &str = include_str!("index.html");
+
+/// How often to check whether clients need a state update.
+const STATE_INTERVAL: Duration = Duration::from_millis(50);
+
+const MAX_REQUEST: usize = 8192;
+
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(1);
+
+lazy_static::lazy_static! {
+    static ref CLIENTS: Mutex<HashMap<u64, UnboundedSender<String>>> = Mutex::new(HashMap::new());
+    /// Clients that haven't been sent the state yet.
+    static ref NEW_CLIENTS: Mutex<Vec<u64>> = Mutex::new(vec![]);
+    /// Buttons pressed and axes moved by each client, as (pad, code).
+    static ref HELD: Mutex<HashMap<u64, Held>> = Mutex::new(HashMap::new());
+    /// When clients were last checked, and what they were sent.
+    static ref LAST_STATE: Mutex<(Option<Instant>, Value)> = Mutex::new((None, Value::Null));
+}
+
+#[derive(Default)]
+struct Held {
+    buttons: HashSet<(usize, i32)>,
+    axes: HashSet<(usize, i32)>,
+}
+
+/// Sends a JSON value to one client, or all of them.
+pub fn send(client: Option<u64>, value: &Value) {
+    let text = value.to_string();
+    let mut clients = CLIENTS.lock();
+    // Anything that can't be sent to has disconnected
+    clients.retain(|id, tx| {
+        let wanted = client.map_or(true, |x| x == *id);
+        !wanted || tx.send(text.clone()).is_ok()
+    });
+}
+
+fn state_message(scripts: &Scripts) -> Value {
+    let mut state = crate::ipc::state(scripts);
+    state["type"] = json!("state");
+    state
+}
+
+/// Sends clients the state when it has changed, and to new clients. Called
+/// on the dispatcher thread every tick.
+pub fn update(scripts: &Scripts) {
+    let mut last = LAST_STATE.lock();
+    if last.0.is_some_and(|x| x.elapsed() < STATE_INTERVAL) || CLIENTS.lock().is_empty() {
+        return;
+    }
+    last.0 = Some(Instant::now());
+
+    let state = state_message(scripts);
+    let new = std::mem::take(&mut *NEW_CLIENTS.lock());
+    if state != last.1 {
+        send(None, &state);
+        last.1 = state;
+    } else {
+        for id in new {
+            send(Some(id), &state);
+        }
+    }
+}
+
+/// Handles a message from a browser on the dispatcher thread.
+pub fn handle(scripts: &Scripts, client: u64, msg: Value) -> mlua::Result<()> {
+    let mut handled = false;
+    for (_, l) in scripts.running() {
+        if let Some(f) = l.globals().get::<_, Option<mlua::Function>>("on_web_recv")? {
+            f.call::<_, ()>((l.to_value(&msg)?, client))?;
+            handled = true;
+        }
+    }
+    if handled {
+        return Ok(());
+    }
+
+    let idx = msg.get("pad").and_then(|x| x.as_u64()).unwrap_or(1) as usize;
+    let code = msg.get("code").and_then(|x| x.as_i64()).map(|x| x as i32);
+    match (msg.get("type").and_then(|x| x.as_str()), code) {
+        (Some("button"), Some(code)) => {
+            let pressed = msg.get("pressed").and_then(|x| x.as_bool()).unwrap_or(false);
+            let mut held = HELD.lock();
+            let buttons = &mut held.entry(client).or_default().buttons;
+            match pressed {
+                true => buttons.insert((idx, code)),
+                false => buttons.remove(&(idx, code)),
+            };
+            drop(held);
+            set_button(scripts, idx, code, pressed)
+        },
+        (Some("axis"), Some(code)) => {
+            let value = msg.get("value").and_then(|x| x.as_f64()).unwrap_or(0.0) as f32;
+            HELD.lock().entry(client).or_default().axes.insert((idx, code));
+            set_axis(scripts, idx, code, value.clamp(-1.0, 1.0))
+        },
+        (Some("disconnect"), _) => {
+            let held = HELD.lock().remove(&client).unwrap_or_default();
+            for (idx, code) in held.buttons {
+                set_button(scripts, idx, code, false)?;
+            }
+            for (idx, code) in held.axes {
+                set_axis(scripts, idx, code, 0.0)?;
+            }
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
+
+fn set_button(scripts: &Scripts, idx: usize, code: i32, pressed: bool) -> mlua::Result<()> {
+    match api::gamepad::get(scripts.running().map(|(_, l)| l), idx) {
+        Some((_, pad)) => pad.0.lock().set_button(code, pressed),
+        None => Ok(()),
+    }
+}
+
+fn set_axis(scripts: &Scripts, idx: usize, code: i32, value: f32) -> mlua::Result<()> {
+    // Filters run in the Lua state of whichever script made the pad
+    match api::gamepad::get(scripts.running().map(|(_, l)| l), idx) {
+        Some((l, pad)) => pad.set_axis(l, code, value),
+        None => Ok(()),
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Finds what to send for a request path, either the built-in page or a
+/// file under `root`.
+async fn resolve(root: Option<&Path>, path: &str) -> Option<(Vec<u8>, &'static str)> {
+    let path = path.split(['?', '#']).next().unwrap_or("/");
+    let root = match root {
+        Some(x) => x,
+        None if path == "/" || path == "/index.html" => return Some((INDEX.as_bytes().to_vec(), "text/html; charset=utf-8")),
+        None => return None,
+    };
+
+    let rel = Path::new(path.trim_start_matches('/'));
+    if rel.components().any(|x| !matches!(x, Component::Normal(_))) {
+        return None;
+    }
+    let mut file = root.join(rel);
+    if file.is_dir() {
+        file = file.join("index.html");
+    }
+
+    let data = tokio::fs::read(&file).await.ok()?;
+    Some((data, content_type(&file)))
+}
+
+async fn http(mut stream: TcpStream, root: Option<PathBuf>) -> anyhow::Result<()> {
+    let mut buf = vec![];
+    while !buf.windows(4).any(|x| x == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST {
+            anyhow::bail!("request is too big");
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or("/"));
+
+    let (status, body, kind) = if method != "GET" && method != "HEAD" {
+        ("405 Method Not Allowed", b"method not allowed\n".to_vec(), "text/plain")
+    } else {
+        match resolve(root.as_deref(), path).await {
+            Some((body, kind)) => ("200 OK", body, kind),
+            None => ("404 Not Found", b"not found\n".to_vec(), "text/plain"),
+        }
+    };
+
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, kind, body.len(),
+    );
+    stream.write_all(header.as_bytes()).await?;
+    if method != "HEAD" {
+        stream.write_all(&body).await?;
+    }
+
+    Ok(())
+}
+
+async fn websocket(stream: TcpStream, sender: MessageSender) -> anyhow::Result<()> {
+    let ws = tokio_tungstenite::accept_async(stream).await?;
+    let (mut sink, mut incoming) = ws.split();
+
+    let id = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
+    let (tx, mut rx) = unbounded_channel();
+    CLIENTS.lock().insert(id, tx);
+    NEW_CLIENTS.lock().push(id);
+    debug!("Web client {} connected", id);
+
+    let res: anyhow::Result<()> = async {
+        loop {
+            tokio::select! {
+                msg = incoming.next() => match msg {
+                    Some(Ok(WsMessage::Text(text))) => match serde_json::from_str::<Value>(&text) {
+                        Ok(x) => {
+                            if sender.send(Message::Web(id, x)).is_err() {
+                                break;
+                            }
+                        },
+                        Err(e) => warn!("Bad message from web client {}: {}", id, e),
+                    },
+                    Some(Ok(WsMessage::Close(_))) | None => break,
+                    Some(Ok(_)) => {},
+                    Some(Err(e)) => return Err(e.into()),
+                },
+                out = rx.recv() => match out {
+                    Some(text) => sink.send(WsMessage::Text(text)).await?,
+                    None => break,
+                },
+            }
+        }
+        Ok(())
+    }.await;
+
+    CLIENTS.lock().remove(&id);
+    let _ = sender.send(Message::Web(id, json!({"type": "disconnect"})));
+    debug!("Web client {} disconnected", id);
+    res
+}
+
+/// The value of a header, from a lowercased request head.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .filter_map(|x| x.split_once(':'))
+        .find(|(k, _)| k.trim() == name)
+        .map(|(_, v)| v.trim())
+}
+
+/// Browsers let any page open a WebSocket to localhost, but they always say
+/// which page it was. Only the control page's own origin gets in; clients
+/// that aren't browsers don't send one. Headers that didn't all fit can't
+/// be checked, so they don't get in either.
+fn same_origin(head: &str) -> bool {
+    let origin = match header(head, "origin") {
+        Some(x) => x,
+        None => return head.contains("\r\n\r\n"),
+    };
+    let origin = origin.split_once("://").map_or(origin, |(_, x)| x);
+    header(head, "host") == Some(origin)
+}
+
+async fn connection(mut stream: TcpStream, root: Option<PathBuf>, sender: MessageSender) -> anyhow::Result<()> {
+    // Look at the headers without taking them, so the WebSocket handshake
+    // still gets to read them
+    let mut buf = [0u8; 2048];
+    let n = stream.peek(&mut buf).await?;
+    let head = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+    let upgrade = head.lines().any(|x| x.starts_with("upgrade:") && x.contains("websocket"));
+
+    if upgrade && !same_origin(&head) {
+        warn!("Refused a WebSocket from {:?}", header(&head, "origin"));
+        stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+        Ok(())
+    } else if upgrade {
+        websocket(stream, sender).await
+    } else {
+        http(stream, root).await
+    }
+}
+
+/// Starts the server on the tokio runtime. Has to be called from inside it.
+pub fn serve(addr: SocketAddr, root: Option<PathBuf>, sender: MessageSender) -> anyhow::Result<()> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    match &root {
+        Some(root) => info!("Serving {:?} on http://{}", root, addr),
+        None => info!("Serving the control page on http://{}", addr),
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Could not accept web connection: {}", e);
+                    continue;
+                },
+            };
+            let root = root.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                if let Err(e) = connection(stream, root, sender).await {
+                    debug!("Web connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origin() {
+        let head = |origin: &str| format!("get / http/1.1\r\nhost: localhost:8080\r\n{}upgrade: websocket\r\n\r\n", origin);
+        assert!(same_origin(&head("origin: http://localhost:8080\r\n")));
+        assert!(same_origin(&head("")));
+        assert!(!same_origin(&head("origin: https://example.com\r\n")));
+        assert!(!same_origin(&head("origin: http://localhost:8081\r\n")));
+        assert!(!same_origin("get / http/1.1\r\nhost: localhost:8080\r\nupgrade: websocket\r\n"));
+    }
+}